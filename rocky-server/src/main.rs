@@ -27,10 +27,19 @@ impl MessageStore for MessageStoreImpl {
         let cols = p.get_collections().to_vec();
         let sm = rocky::store::Msg {
             subject: None,
-            from: None,
+            from: vec![],
+            to: vec![],
+            cc: vec![],
+            bcc: vec![],
+            reply_to: vec![],
             text: "test".to_string(),
             date: 0,
             eml: b"".to_vec(),
+            headers: vec![],
+            mime: None,
+            message_id: None,
+            in_reply_to: vec![],
+            references: vec![],
         };
 
         self.store.put(&cols, &sm).unwrap();
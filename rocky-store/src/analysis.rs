@@ -0,0 +1,465 @@
+//! Text analysis applied to text fields, both when indexing and when querying.
+
+use rust_stemmers::{Algorithm, Stemmer};
+use std::collections::HashSet;
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// A term produced by an analyzer, with its position in the source text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub position: u32,
+    pub text: String,
+}
+
+pub trait Analyzer: Send + Sync {
+    /// Splits `text` into the terms stored in the index.
+    fn analyze(&self, text: &str) -> Vec<Token>;
+
+    /// Applies the same filters as `analyze` to a single query term, without
+    /// tokenizing it. Returns `None` when the term is filtered out.
+    fn normalize(&self, term: &str) -> Option<String>;
+
+    /// Normalizes a prefix, wildcard or fuzzy pattern. Only character-level filters
+    /// apply: a pattern is not a word, so it must be neither dropped as a stop word nor
+    /// stemmed.
+    fn normalize_pattern(&self, pattern: &str) -> Option<String> {
+        self.normalize(pattern)
+    }
+
+    /// Stable description of the analysis, recorded in the store metadata so that a
+    /// store is never queried with a different analysis than it was indexed with.
+    fn name(&self) -> String;
+}
+
+pub trait Tokenizer: Send + Sync {
+    fn tokenize(&self, text: &str) -> Vec<Token>;
+
+    fn name(&self) -> String;
+}
+
+pub trait TokenFilter: Send + Sync {
+    /// Returns the rewritten token, or `None` to drop it.
+    fn filter(&self, token: String) -> Option<String>;
+
+    fn name(&self) -> String;
+
+    /// Whether the filter rewrites characters independently of the word they are part
+    /// of, which makes it applicable to query patterns.
+    fn character_level(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    English,
+    French,
+}
+
+impl Language {
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Language::English => "en",
+            Language::French => "fr",
+        }
+    }
+
+    fn algorithm(&self) -> Algorithm {
+        match *self {
+            Language::English => Algorithm::English,
+            Language::French => Algorithm::French,
+        }
+    }
+
+    fn stop_words(&self) -> &'static [&'static str] {
+        match *self {
+            Language::English => ENGLISH_STOP_WORDS,
+            Language::French => FRENCH_STOP_WORDS,
+        }
+    }
+}
+
+static ENGLISH_STOP_WORDS: &'static [&'static str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it", "no", "not", "of", "on", "or",
+    "such", "that", "the", "their", "then", "there", "these", "they", "this", "to", "was", "will", "with",
+];
+
+static FRENCH_STOP_WORDS: &'static [&'static str] = &[
+    "au", "aux", "avec", "ce", "ces", "dans", "de", "des", "du", "elle", "en", "et", "eux", "il", "je", "la", "le", "les", "leur",
+    "lui", "ma", "mais", "me", "mes", "moi", "mon", "ne", "nos", "notre", "nous", "on", "ou", "par", "pas", "pour", "qu", "que",
+    "qui", "sa", "se", "ses", "son", "sur", "ta", "te", "tes", "toi", "ton", "tu", "un", "une", "vos", "votre", "vous",
+];
+
+/// Splits on Unicode word boundaries.
+pub struct WordTokenizer;
+
+impl Tokenizer for WordTokenizer {
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        text.unicode_words()
+            .enumerate()
+            .map(|(i, w)| Token {
+                position: i as u32,
+                text: w.to_string(),
+            })
+            .collect()
+    }
+
+    fn name(&self) -> String {
+        "words".to_string()
+    }
+}
+
+/// Splits on Unicode word boundaries like `WordTokenizer`, but indexes runs of
+/// Chinese, Japanese and Korean characters as overlapping n-grams, since those
+/// scripts do not separate words with spaces. Each character of a run is also
+/// indexed alone, at the position of the n-gram it starts, so that single
+/// characters can be searched for.
+pub struct CjkNgramTokenizer {
+    n: usize,
+}
+
+impl CjkNgramTokenizer {
+    pub fn new(n: usize) -> CjkNgramTokenizer {
+        CjkNgramTokenizer { n: n.max(1) }
+    }
+
+    fn flush(&self, run: &mut Vec<char>, position: &mut u32, tokens: &mut Vec<Token>) {
+        let grams: Vec<&[char]> = if run.len() <= self.n {
+            vec![&run[..]]
+        } else {
+            run.windows(self.n).collect()
+        };
+        for (i, c) in run.iter().enumerate() {
+            let at = *position + i as u32;
+            if let Some(gram) = grams.get(i) {
+                tokens.push(Token {
+                    position: at,
+                    text: gram.iter().collect(),
+                });
+            }
+            // grams of a single character are unigrams already
+            if run.len() > 1 && self.n > 1 {
+                tokens.push(Token {
+                    position: at,
+                    text: c.to_string(),
+                });
+            }
+        }
+        *position += run.len() as u32;
+        run.clear();
+    }
+}
+
+fn is_cjk(c: char) -> bool {
+    match c as u32 {
+        0x3040..=0x309F | // Hiragana
+        0x30A0..=0x30FF | // Katakana
+        0x3400..=0x4DBF | // CJK Unified Ideographs Extension A
+        0x4E00..=0x9FFF | // CJK Unified Ideographs
+        0xAC00..=0xD7AF | // Hangul Syllables
+        0xF900..=0xFAFF | // CJK Compatibility Ideographs
+        0xFF66..=0xFF9F | // Halfwidth Katakana
+        0x20000..=0x2FA1F => true, // Supplementary ideographic plane
+        _ => false,
+    }
+}
+
+impl Tokenizer for CjkNgramTokenizer {
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        let mut tokens = vec![];
+        let mut run: Vec<char> = vec![];
+        let mut position = 0;
+        for segment in text.split_word_bounds() {
+            if segment.chars().all(is_cjk) {
+                run.extend(segment.chars());
+                continue;
+            }
+            self.flush(&mut run, &mut position, &mut tokens);
+            if segment.chars().any(|c| c.is_alphanumeric()) {
+                tokens.push(Token {
+                    position: position,
+                    text: segment.to_string(),
+                });
+                position += 1;
+            }
+        }
+        self.flush(&mut run, &mut position, &mut tokens);
+        tokens
+    }
+
+    fn name(&self) -> String {
+        if self.n > 1 {
+            format!("cjk-ngram:1-{}", self.n)
+        } else {
+            "cjk-ngram:1".to_string()
+        }
+    }
+}
+
+/// Unicode case folding. Relies on the full lowercase mapping, which covers
+/// simple case folding for everything but a handful of special cases.
+pub struct CaseFoldFilter;
+
+impl TokenFilter for CaseFoldFilter {
+    fn filter(&self, token: String) -> Option<String> {
+        Some(token.to_lowercase())
+    }
+
+    fn name(&self) -> String {
+        "casefold".to_string()
+    }
+
+    fn character_level(&self) -> bool {
+        true
+    }
+}
+
+/// NFKC normalization, so that compatibility forms (ligatures, full-width
+/// letters...) index as their canonical characters.
+pub struct NfkcFilter;
+
+impl TokenFilter for NfkcFilter {
+    fn filter(&self, token: String) -> Option<String> {
+        Some(token.nfkc().collect())
+    }
+
+    fn name(&self) -> String {
+        "nfkc".to_string()
+    }
+
+    fn character_level(&self) -> bool {
+        true
+    }
+}
+
+/// Removes diacritics by decomposing the token and dropping combining marks.
+pub struct StripDiacriticsFilter;
+
+impl TokenFilter for StripDiacriticsFilter {
+    fn filter(&self, token: String) -> Option<String> {
+        let stripped: String = token.nfd().filter(|c| !is_combining_mark(*c)).nfc().collect();
+        if stripped.is_empty() {
+            None
+        } else {
+            Some(stripped)
+        }
+    }
+
+    fn name(&self) -> String {
+        "strip-diacritics".to_string()
+    }
+
+    fn character_level(&self) -> bool {
+        true
+    }
+}
+
+/// Snowball stemming. Expects lowercased input, so it belongs after `CaseFoldFilter`.
+pub struct StemFilter {
+    language: Language,
+    stemmer: Stemmer,
+}
+
+impl StemFilter {
+    pub fn new(language: Language) -> StemFilter {
+        StemFilter {
+            language: language,
+            stemmer: Stemmer::create(language.algorithm()),
+        }
+    }
+}
+
+impl TokenFilter for StemFilter {
+    fn filter(&self, token: String) -> Option<String> {
+        Some(self.stemmer.stem(&token).into_owned())
+    }
+
+    fn name(&self) -> String {
+        format!("stem:{}", self.language.code())
+    }
+}
+
+/// Drops the most common words of a language. Expects lowercased input and must run
+/// before any stemming.
+pub struct StopWordFilter {
+    language: Language,
+    words: HashSet<&'static str>,
+}
+
+impl StopWordFilter {
+    pub fn new(language: Language) -> StopWordFilter {
+        StopWordFilter {
+            language: language,
+            words: language.stop_words().iter().cloned().collect(),
+        }
+    }
+}
+
+impl TokenFilter for StopWordFilter {
+    fn filter(&self, token: String) -> Option<String> {
+        if self.words.contains(token.as_str()) {
+            None
+        } else {
+            Some(token)
+        }
+    }
+
+    fn name(&self) -> String {
+        format!("stop:{}", self.language.code())
+    }
+}
+
+/// A tokenizer followed by a chain of filters.
+pub struct TextAnalyzer {
+    tokenizer: Box<Tokenizer>,
+    filters: Vec<Box<TokenFilter>>,
+}
+
+impl TextAnalyzer {
+    pub fn new(tokenizer: Box<Tokenizer>) -> TextAnalyzer {
+        TextAnalyzer {
+            tokenizer: tokenizer,
+            filters: vec![],
+        }
+    }
+
+    pub fn filter(mut self, filter: Box<TokenFilter>) -> TextAnalyzer {
+        self.filters.push(filter);
+        self
+    }
+
+    /// The default pipeline followed by stop-word removal and stemming for `language`.
+    pub fn language(language: Language) -> TextAnalyzer {
+        TextAnalyzer::default()
+            .filter(Box::new(StopWordFilter::new(language)))
+            .filter(Box::new(StemFilter::new(language)))
+    }
+
+    fn apply(&self, term: String) -> Option<String> {
+        let mut term = term;
+        for f in self.filters.iter() {
+            term = f.filter(term)?;
+        }
+        Some(term)
+    }
+}
+
+impl Default for TextAnalyzer {
+    fn default() -> TextAnalyzer {
+        TextAnalyzer::new(Box::new(CjkNgramTokenizer::new(2)))
+            .filter(Box::new(NfkcFilter))
+            .filter(Box::new(CaseFoldFilter))
+    }
+}
+
+impl Analyzer for TextAnalyzer {
+    fn analyze(&self, text: &str) -> Vec<Token> {
+        self.tokenizer
+            .tokenize(text)
+            .into_iter()
+            .filter_map(|t| {
+                let position = t.position;
+                self.apply(t.text).map(|text| Token {
+                    position: position,
+                    text: text,
+                })
+            })
+            .collect()
+    }
+
+    fn normalize(&self, term: &str) -> Option<String> {
+        self.apply(term.to_string())
+    }
+
+    fn normalize_pattern(&self, pattern: &str) -> Option<String> {
+        let mut pattern = pattern.to_string();
+        for f in self.filters.iter().filter(|f| f.character_level()) {
+            pattern = f.filter(pattern)?;
+        }
+        Some(pattern)
+    }
+
+    fn name(&self) -> String {
+        let mut name = self.tokenizer.name();
+        for f in self.filters.iter() {
+            name.push('|');
+            name.push_str(&f.name());
+        }
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(tokens: Vec<Token>) -> Vec<(u32, String)> {
+        tokens.into_iter().map(|t| (t.position, t.text)).collect()
+    }
+
+    #[test]
+    fn test_default_analyzer() {
+        let analyzer = TextAnalyzer::default();
+        assert_eq!(analyzer.name(), "cjk-ngram:1-2|nfkc|casefold");
+        assert_eq!(
+            texts(analyzer.analyze("Hello, ＷＯＲＬＤ ﬁle")),
+            vec![(0, "hello".to_string()), (1, "world".to_string()), (2, "file".to_string())]
+        );
+        assert_eq!(analyzer.normalize("ÉTÉ"), Some("été".to_string()));
+    }
+
+    #[test]
+    fn test_filters() {
+        assert_eq!(StripDiacriticsFilter.filter("café".to_string()), Some("cafe".to_string()));
+        assert_eq!(StripDiacriticsFilter.filter("\u{301}".to_string()), None);
+        let analyzer = TextAnalyzer::new(Box::new(WordTokenizer)).filter(Box::new(StripDiacriticsFilter));
+        assert_eq!(analyzer.name(), "words|strip-diacritics");
+        assert_eq!(analyzer.normalize("Noël"), Some("Noel".to_string()));
+    }
+
+    #[test]
+    fn test_english_analyzer() {
+        let analyzer = TextAnalyzer::language(Language::English);
+        assert_eq!(analyzer.name(), "cjk-ngram:1-2|nfkc|casefold|stop:en|stem:en");
+        // stop words are dropped but keep their position
+        assert_eq!(
+            texts(analyzer.analyze("The cats are running")),
+            vec![(1, "cat".to_string()), (3, "run".to_string())]
+        );
+        assert_eq!(analyzer.normalize("The"), None);
+        assert_eq!(analyzer.normalize("Connected"), Some("connect".to_string()));
+    }
+
+    #[test]
+    fn test_french_analyzer() {
+        let analyzer = TextAnalyzer::language(Language::French);
+        assert_eq!(
+            texts(analyzer.analyze("Les chats de la maison")),
+            vec![(1, "chat".to_string()), (4, "maison".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_cjk_ngrams() {
+        let tokenizer = CjkNgramTokenizer::new(2);
+        assert_eq!(tokenizer.name(), "cjk-ngram:1-2");
+        let t = |position: u32, text: &str| (position, text.to_string());
+        assert_eq!(
+            texts(tokenizer.tokenize("東京都")),
+            vec![t(0, "東京"), t(0, "東"), t(1, "京都"), t(1, "京"), t(2, "都")]
+        );
+        // short runs are kept whole, other words are split on word boundaries
+        assert_eq!(
+            texts(tokenizer.tokenize("hello 日 world")),
+            vec![(0, "hello".to_string()), (1, "日".to_string()), (2, "world".to_string())]
+        );
+        assert_eq!(texts(tokenizer.tokenize("mail東京 box")), vec![t(0, "mail"), t(1, "東京"), t(1, "東"), t(2, "京"), t(3, "box")]);
+        assert!(tokenizer.tokenize("").is_empty());
+
+        let tokenizer = CjkNgramTokenizer::new(1);
+        assert_eq!(tokenizer.name(), "cjk-ngram:1");
+        assert_eq!(texts(tokenizer.tokenize("東京")), vec![t(0, "東"), t(1, "京")]);
+    }
+}
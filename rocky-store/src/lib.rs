@@ -1,8 +1,15 @@
 extern crate byteorder;
 extern crate roaring;
 extern crate rocksdb;
+extern crate rust_stemmers;
+extern crate unicode_normalization;
 extern crate unicode_segmentation;
 
 extern crate rand;
 
+pub mod analysis;
+pub mod matcher;
+pub mod mime;
+pub mod query;
 pub mod store;
+pub mod thread;
@@ -0,0 +1,168 @@
+//! Term matchers used to expand a pattern into the index terms it covers.
+
+/// Literal part of a wildcard pattern before its first `*` or `?`.
+pub fn wildcard_prefix(pattern: &str) -> &str {
+    match pattern.find(|c: char| c == '*' || c == '?') {
+        Some(i) => &pattern[..i],
+        None => pattern,
+    }
+}
+
+/// Matches `term` against a pattern where `*` stands for any run of characters
+/// and `?` for exactly one.
+pub fn wildcard_match(pattern: &str, term: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = term.chars().collect();
+
+    let (mut pi, mut ti) = (0, 0);
+    // position of the last `*` seen and the term index it was tried at
+    let mut star: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+/// Levenshtein automaton over the characters of a term, stepped one character at a
+/// time so that whole branches of the ordered term index can be skipped as soon as
+/// no completion of the current prefix can be within `max_edits`.
+pub struct Levenshtein {
+    query: Vec<char>,
+    max_edits: u32,
+}
+
+impl Levenshtein {
+    pub fn new(query: &str, max_edits: u32) -> Levenshtein {
+        Levenshtein {
+            query: query.chars().collect(),
+            max_edits: max_edits,
+        }
+    }
+
+    /// State before any character was read.
+    pub fn start(&self) -> Vec<u32> {
+        (0..(self.query.len() as u32 + 1)).collect()
+    }
+
+    pub fn step(&self, state: &[u32], c: char) -> Vec<u32> {
+        let mut next = Vec::with_capacity(state.len());
+        next.push(state[0] + 1);
+        for i in 1..state.len() {
+            let cost = if self.query[i - 1] == c { 0 } else { 1 };
+            let v = (state[i] + 1).min(next[i - 1] + 1).min(state[i - 1] + cost);
+            next.push(v);
+        }
+        next
+    }
+
+    pub fn is_match(&self, state: &[u32]) -> bool {
+        state[state.len() - 1] <= self.max_edits
+    }
+
+    pub fn can_match(&self, state: &[u32]) -> bool {
+        state.iter().any(|v| *v <= self.max_edits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcard_prefix() {
+        assert_eq!(wildcard_prefix("foo*bar"), "foo");
+        assert_eq!(wildcard_prefix("fo?"), "fo");
+        assert_eq!(wildcard_prefix("*foo"), "");
+        assert_eq!(wildcard_prefix("foo"), "foo");
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("foo", "foo"));
+        assert!(!wildcard_match("foo", "food"));
+        assert!(wildcard_match("f?o", "fao"));
+        assert!(!wildcard_match("f?o", "fo"));
+        assert!(wildcard_match("f*", "f"));
+        assert!(wildcard_match("f*d", "food"));
+        assert!(!wildcard_match("f*d", "foods"));
+        // backtracking over several candidate positions for the star
+        assert!(wildcard_match("a*b*c", "abxbyc"));
+        assert!(!wildcard_match("a*b*c", "abxbyd"));
+    }
+
+    #[test]
+    fn test_wildcard_match_edge_cases() {
+        assert!(wildcard_match("", ""));
+        assert!(!wildcard_match("", "a"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("*", "anything"));
+        assert!(wildcard_match("*ing", "anything"));
+        assert!(wildcard_match("**", "a"));
+        assert!(wildcard_match("é?é", "éaé"));
+        assert!(wildcard_match("?", "日"));
+        assert!(!wildcard_match("?", "日本"));
+    }
+
+    fn matches(automaton: &Levenshtein, term: &str) -> bool {
+        let mut state = automaton.start();
+        for c in term.chars() {
+            state = automaton.step(&state, c);
+        }
+        automaton.is_match(&state)
+    }
+
+    #[test]
+    fn test_levenshtein() {
+        let automaton = Levenshtein::new("kitten", 2);
+        assert!(matches(&automaton, "kitten"));
+        assert!(matches(&automaton, "sitten"));
+        assert!(matches(&automaton, "kittn"));
+        assert!(matches(&automaton, "kittens"));
+        assert!(matches(&automaton, "sittin"));
+        assert!(!matches(&automaton, "sitting"));
+        assert!(!matches(&automaton, "kit"));
+    }
+
+    #[test]
+    fn test_levenshtein_exact() {
+        let automaton = Levenshtein::new("abc", 0);
+        assert!(matches(&automaton, "abc"));
+        assert!(!matches(&automaton, "abd"));
+        assert!(!matches(&automaton, "ab"));
+    }
+
+    #[test]
+    fn test_levenshtein_empty_and_multibyte() {
+        let automaton = Levenshtein::new("", 1);
+        assert!(matches(&automaton, ""));
+        assert!(matches(&automaton, "a"));
+        assert!(!matches(&automaton, "ab"));
+
+        // edits count characters, not bytes
+        let automaton = Levenshtein::new("café", 1);
+        assert!(matches(&automaton, "cafe"));
+        assert!(matches(&automaton, "cafés"));
+        assert!(!matches(&automaton, "cafes"));
+    }
+
+    #[test]
+    fn test_levenshtein_can_match() {
+        let automaton = Levenshtein::new("abc", 1);
+        let state = automaton.step(&automaton.start(), 'x');
+        assert!(automaton.can_match(&state));
+        let state = automaton.step(&state, 'y');
+        assert!(!automaton.can_match(&state));
+    }
+}
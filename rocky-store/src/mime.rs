@@ -0,0 +1,188 @@
+//! Parameters of structured MIME headers such as Content-Type and Content-Disposition.
+
+use std::str;
+
+/// `name=value` parameters following the first `;` of a header value, with lowercased
+/// names and unquoted values.
+pub fn params(value: &str) -> Vec<(String, String)> {
+    let mut ret = vec![];
+    let mut chars = value.chars().skip_while(|c| *c != ';').peekable();
+    while chars.next().is_some() {
+        let mut name = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == '=' || c == ';' {
+                break;
+            }
+            name.push(c);
+            chars.next();
+        }
+        if chars.peek() != Some(&'=') {
+            continue;
+        }
+        chars.next();
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+
+        let mut v = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => v.extend(chars.next()),
+                    c => v.push(c),
+                }
+            }
+            // skip anything up to the next parameter
+            while chars.peek().map_or(false, |c| *c != ';') {
+                chars.next();
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c == ';' {
+                    break;
+                }
+                v.push(c);
+                chars.next();
+            }
+            v = v.trim().to_string();
+        }
+        ret.push((name.trim().to_lowercase(), v));
+    }
+    ret
+}
+
+/// Value of the parameter `name` of a header value. RFC 2231 extended values
+/// (`name*=charset'lang'value`) are percent-decoded and continuations (`name*0=`,
+/// `name*1*=`...) joined; they take precedence over a plain `name=`.
+pub fn param(value: &str, name: &str) -> Option<String> {
+    let mut plain = None;
+    let mut extended = None;
+    // continuation index, whether it is percent-encoded, raw value
+    let mut sections: Vec<(u32, bool, String)> = vec![];
+    for (key, v) in params(value) {
+        if key == name {
+            plain = Some(v);
+            continue;
+        }
+        if !key.starts_with(name) || !key[name.len()..].starts_with('*') {
+            continue;
+        }
+        let rest = &key[name.len() + 1..];
+        if rest.is_empty() {
+            extended = Some(v);
+            continue;
+        }
+        let (index, encoded) = if rest.ends_with('*') {
+            (&rest[..rest.len() - 1], true)
+        } else {
+            (rest, false)
+        };
+        if let Ok(index) = index.parse::<u32>() {
+            sections.push((index, encoded, v));
+        }
+    }
+
+    if let Some(v) = extended {
+        let (charset, v) = split_charset(&v);
+        return Some(decode_charset(&percent_decode(v), charset.as_ref().map(|c| c.as_str())));
+    }
+    if !sections.is_empty() {
+        sections.sort_by_key(|s| s.0);
+        let mut charset = None;
+        let mut bytes = vec![];
+        for (i, &(_, encoded, ref v)) in sections.iter().enumerate() {
+            if !encoded {
+                bytes.extend(v.as_bytes());
+                continue;
+            }
+            let mut v = v.as_str();
+            if i == 0 {
+                let (c, rest) = split_charset(v);
+                charset = c;
+                v = rest;
+            }
+            bytes.extend(percent_decode(v));
+        }
+        return Some(decode_charset(&bytes, charset.as_ref().map(|c| c.as_str())));
+    }
+    plain
+}
+
+/// Splits `charset'lang'value`, tolerating values without the two quotes.
+fn split_charset(value: &str) -> (Option<String>, &str) {
+    let mut parts = value.splitn(3, '\'');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(charset), Some(_), Some(rest)) => (Some(charset.to_lowercase()), rest),
+        _ => (None, value),
+    }
+}
+
+fn percent_decode(value: &str) -> Vec<u8> {
+    let bytes = value.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(b) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                ret.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        ret.push(bytes[i]);
+        i += 1;
+    }
+    ret
+}
+
+fn decode_charset(bytes: &[u8], charset: Option<&str>) -> String {
+    match charset {
+        Some("iso-8859-1") | Some("latin1") => bytes.iter().map(|b| *b as char).collect(),
+        _ => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_params() {
+        assert_eq!(
+            params("attachment; filename=\"a; b.pdf\"; size=12"),
+            vec![
+                ("filename".to_string(), "a; b.pdf".to_string()),
+                ("size".to_string(), "12".to_string()),
+            ]
+        );
+        assert_eq!(
+            params("text/plain; Name = \"say \\\"hi\\\".txt\" ;broken"),
+            vec![("name".to_string(), "say \"hi\".txt".to_string())]
+        );
+        assert!(params("inline").is_empty());
+    }
+
+    #[test]
+    fn test_param() {
+        assert_eq!(param("attachment; filename=report.pdf", "filename"), Some("report.pdf".to_string()));
+        assert_eq!(param("attachment; filename=report.pdf", "name"), None);
+        assert_eq!(
+            param("attachment; filename=resume.pdf; filename*=UTF-8''r%C3%A9sum%C3%A9.pdf", "filename"),
+            Some("résumé.pdf".to_string())
+        );
+        assert_eq!(param("attachment; filename*=iso-8859-1'fr'%E9t%E9", "filename"), Some("été".to_string()));
+        assert_eq!(
+            param("attachment; filename*1*=%C3%A9.pdf; filename*0*=UTF-8''r%C3%A9sum", "filename"),
+            Some("résumé.pdf".to_string())
+        );
+        assert_eq!(
+            param("attachment; filename*0=\"long \"; filename*1=\"name.txt\"", "filename"),
+            Some("long name.txt".to_string())
+        );
+        // malformed escapes are kept as they are
+        assert_eq!(param("attachment; filename*=UTF-8''100%", "filename"), Some("100%".to_string()));
+    }
+}
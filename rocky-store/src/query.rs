@@ -0,0 +1,73 @@
+use store::{Field, Flag};
+
+/// Boolean query tree evaluated by `Store::query` against the index bitmaps.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Term(Field, String),
+    Prefix(Field, String),
+    Wildcard(Field, String),
+    Fuzzy(Field, String, u32),
+    Phrase(Field, Vec<String>),
+    Near(Field, String, String, u32),
+    Col(u32),
+    /// A collection and every collection below it.
+    Subtree(u32),
+    Date(i64),
+    /// Dates in `[from, to)`.
+    DateRange(i64, i64),
+    Flag(Flag),
+    /// Messages of a thread, as returned by `Store::thread_of`.
+    Thread(u32),
+    /// A header configured with `StoreConfig::header`, by name and value.
+    Header(String, String),
+    HasAttachment,
+    /// Messages with an attachment of at least this many bytes.
+    Larger(u64),
+    /// Messages with an attachment of less than this many bytes.
+    Smaller(u64),
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Not(Box<Query>),
+}
+
+impl Query {
+    pub fn term(field: Field, term: &str) -> Query {
+        Query::Term(field, term.to_string())
+    }
+
+    pub fn prefix(field: Field, prefix: &str) -> Query {
+        Query::Prefix(field, prefix.to_string())
+    }
+
+    pub fn wildcard(field: Field, pattern: &str) -> Query {
+        Query::Wildcard(field, pattern.to_string())
+    }
+
+    pub fn fuzzy(field: Field, term: &str, max_edits: u32) -> Query {
+        Query::Fuzzy(field, term.to_string(), max_edits)
+    }
+
+    pub fn phrase(field: Field, terms: &[&str]) -> Query {
+        Query::Phrase(field, terms.iter().map(|t| t.to_string()).collect())
+    }
+
+    pub fn near(field: Field, a: &str, b: &str, distance: u32) -> Query {
+        Query::Near(field, a.to_string(), b.to_string(), distance)
+    }
+
+    pub fn header(name: &str, value: &str) -> Query {
+        Query::Header(name.to_string(), value.to_string())
+    }
+
+    pub fn and(queries: Vec<Query>) -> Query {
+        Query::And(queries)
+    }
+
+    pub fn or(queries: Vec<Query>) -> Query {
+        Query::Or(queries)
+    }
+
+    pub fn not(query: Query) -> Query {
+        Query::Not(Box::new(query))
+    }
+}
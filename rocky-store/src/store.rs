@@ -10,15 +10,356 @@ use std::ops::Deref;
 use std::string::String;
 use byteorder::{BigEndian, ByteOrder};
 
-use unicode_segmentation::UnicodeSegmentation;
 use roaring::bitmap::RoaringBitmap;
 use std::str;
 
+use analysis::{Analyzer, TextAnalyzer, Token};
+use matcher;
+use mime;
+use query::Query;
+use thread;
+
 pub type DocIdSet = RoaringBitmap;
 
+/// Default cap on the number of index terms a prefix or wildcard query expands to.
+pub const MAX_EXPANSIONS: usize = 1024;
+
 struct DocId(u32);
+#[derive(Debug, Clone, PartialEq)]
 pub struct Collection(pub u32, pub String);
 
+/// Special use of a collection, as in RFC 6154.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Inbox,
+    Sent,
+    Drafts,
+    Trash,
+    Junk,
+    Archive,
+}
+
+impl Role {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Role::Inbox => "inbox",
+            Role::Sent => "sent",
+            Role::Drafts => "drafts",
+            Role::Trash => "trash",
+            Role::Junk => "junk",
+            Role::Archive => "archive",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Role> {
+        match name {
+            "inbox" => Some(Role::Inbox),
+            "sent" => Some(Role::Sent),
+            "drafts" => Some(Role::Drafts),
+            "trash" => Some(Role::Trash),
+            "junk" => Some(Role::Junk),
+            "archive" => Some(Role::Archive),
+            _ => None,
+        }
+    }
+}
+
+/// Metadata kept next to a collection name, under `col_meta#<id>` in the `col` column family.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollectionMeta {
+    /// Creation time, in seconds since the epoch, 0 when unknown.
+    pub created: i64,
+    /// IMAP UIDVALIDITY: UIDs of the collection are only comparable for a same value.
+    pub uid_validity: u32,
+    pub role: Option<Role>,
+}
+
+impl CollectionMeta {
+    fn key(col: u32) -> Vec<u8> {
+        let mut key = Vec::new();
+        key.extend(b"col_meta#".iter());
+        key.extend(&DocId(col).write()[..]);
+        key
+    }
+
+    fn deserialize(data: &[u8]) -> CollectionMeta {
+        CollectionMeta {
+            created: BigEndian::read_i64(&data[0..8]),
+            uid_validity: BigEndian::read_u32(&data[8..12]),
+            role: str::from_utf8(&data[12..]).ok().and_then(Role::parse),
+        }
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut data: Vec<u8> = vec![0; 12];
+        BigEndian::write_i64(&mut data[0..8], self.created);
+        BigEndian::write_u32(&mut data[8..12], self.uid_validity);
+        if let Some(role) = self.role {
+            data.extend(role.name().as_bytes());
+        }
+        data
+    }
+}
+
+/// What happens to the messages of a deleted collection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeleteMode {
+    /// Messages only lose their membership in the collection.
+    Untag,
+    /// Messages are deleted from the store, whatever other collections hold them.
+    Expunge,
+}
+
+/// IMAP system flags and user keywords, each indexed as a bitmap under `msg#flag#<name>`.
+/// Keywords are IMAP atoms, so they never start with a backslash and never collide with
+/// system flags. They are case-insensitive and indexed lowercased.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Flag {
+    Seen,
+    Answered,
+    Flagged,
+    Deleted,
+    Draft,
+    Keyword(String),
+}
+
+impl Flag {
+    pub fn name(&self) -> &str {
+        match *self {
+            Flag::Seen => "\\Seen",
+            Flag::Answered => "\\Answered",
+            Flag::Flagged => "\\Flagged",
+            Flag::Deleted => "\\Deleted",
+            Flag::Draft => "\\Draft",
+            Flag::Keyword(ref keyword) => keyword,
+        }
+    }
+
+    /// A validated keyword, in the case it is indexed with.
+    pub fn keyword(name: &str) -> Result<Flag, StoreError> {
+        let keyword = Flag::Keyword(name.to_lowercase());
+        keyword.validate()?;
+        Ok(keyword)
+    }
+
+    fn validate(&self) -> Result<(), StoreError> {
+        if let Flag::Keyword(ref keyword) = *self {
+            // ATOM-CHAR of RFC 3501: printable ASCII but atom-specials
+            let atom = |c: char| c > ' ' && c < '\x7f' && !"(){%*\"\\]".contains(c);
+            if keyword.is_empty() || !keyword.chars().all(atom) {
+                return Err(StoreError::InvalidArgument(format!("invalid keyword {}", keyword)));
+            }
+        }
+        Ok(())
+    }
+
+    fn key(&self) -> Vec<u8> {
+        match *self {
+            Flag::Keyword(ref keyword) => Field::Flag.key(keyword.to_lowercase().as_bytes()),
+            _ => Field::Flag.key(self.name().as_bytes()),
+        }
+    }
+}
+
+/// A change logged in the `mod` column family for one collection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Add(DocIdSet),
+    Remove(DocIdSet),
+    Flags(DocIdSet),
+}
+
+impl Change {
+    fn deserialize(data: &[u8]) -> Option<Change> {
+        // entries are `<op>#<bitmap>`, early entries only hold the operation
+        let sep = data.iter().position(|b| *b == b'#').unwrap_or(data.len());
+        let docs = if sep < data.len() {
+            RoaringBitmap::deserialize_from(&data[sep + 1..]).unwrap()
+        } else {
+            DocIdSet::default()
+        };
+        match &data[..sep] {
+            b"add" => Some(Change::Add(docs)),
+            b"remove" => Some(Change::Remove(docs)),
+            b"flags" => Some(Change::Flags(docs)),
+            _ => None,
+        }
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let (op, docs): (&[u8], &DocIdSet) = match *self {
+            Change::Add(ref docs) => (&b"add"[..], docs),
+            Change::Remove(ref docs) => (&b"remove"[..], docs),
+            Change::Flags(ref docs) => (&b"flags"[..], docs),
+        };
+        let mut data: Vec<u8> = Vec::with_capacity(op.len() + 1 + docs.serialized_size());
+        data.extend(op);
+        data.push(b'#');
+        docs.serialize_into(&mut data).unwrap();
+        data
+    }
+}
+
+/// Changes of a collection after a given modseq, in modseq order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Changes {
+    pub changes: Vec<(u64, Change)>,
+    /// Highest modseq committed by the store, to pass to the next `changes_since`.
+    pub modseq: u64,
+}
+
+/// Sort key of `Store::search`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sort {
+    Date(Order),
+}
+
+/// Opaque position after the last result of a page, to pass back to `Store::search`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor(Vec<u8>);
+
+impl Cursor {
+    fn new(date: i64, doc_id: u32) -> Cursor {
+        let mut data = vec![0; 12];
+        BigEndian::write_i64(&mut data[0..8], date);
+        BigEndian::write_u32(&mut data[8..12], doc_id);
+        Cursor(data)
+    }
+
+    fn position(&self) -> Option<(i64, u32)> {
+        if self.0.len() != 12 {
+            return None;
+        }
+        Some((BigEndian::read_i64(&self.0[0..8]), BigEndian::read_u32(&self.0[8..12])))
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Cursor {
+        Cursor(data)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0[..]
+    }
+}
+
+/// One page of sorted results, with the cursor of the next page if there is one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page {
+    pub doc_ids: Vec<u32>,
+    pub next: Option<Cursor>,
+}
+
+/// Number of messages of a result set held by one collection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Facet {
+    pub col: u32,
+    pub count: u64,
+    /// Messages of `count` without the `\Seen` flag.
+    pub unread: u64,
+}
+
+/// Result of a fuzzy lookup: the matching documents and the index terms they were
+/// found under, usable as "did you mean" suggestions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuzzyMatch {
+    pub docs: DocIdSet,
+    pub terms: Vec<String>,
+}
+
+/// Direction used when walking the date index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+/// Fields shredded into the index, each one under its own `msg#<field>#` prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Field {
+    From,
+    To,
+    Cc,
+    Bcc,
+    ReplyTo,
+    Subject,
+    Body,
+    Date,
+    Cols,
+    Flag,
+    Thread,
+    Filename,
+    MimeType,
+    Size,
+    Has,
+}
+
+impl Field {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Field::From => "from",
+            Field::To => "to",
+            Field::Cc => "cc",
+            Field::Bcc => "bcc",
+            Field::ReplyTo => "reply-to",
+            Field::Subject => "subject",
+            Field::Body => "body",
+            Field::Date => "date",
+            Field::Cols => "cols",
+            Field::Flag => "flag",
+            Field::Thread => "thread",
+            Field::Filename => "filename",
+            Field::MimeType => "mimetype",
+            Field::Size => "size",
+            Field::Has => "has",
+        }
+    }
+
+    pub fn prefix(&self) -> &'static str {
+        match *self {
+            Field::From => "msg#from#",
+            Field::To => "msg#to#",
+            Field::Cc => "msg#cc#",
+            Field::Bcc => "msg#bcc#",
+            Field::ReplyTo => "msg#reply-to#",
+            Field::Subject => "msg#subject#",
+            Field::Body => "msg#body#",
+            Field::Date => "msg#date#",
+            Field::Cols => "msg#cols#",
+            Field::Flag => "msg#flag#",
+            Field::Thread => "msg#thread#",
+            Field::Filename => "msg#filename#",
+            Field::MimeType => "msg#mimetype#",
+            Field::Size => "msg#size#",
+            Field::Has => "msg#has#",
+        }
+    }
+
+    /// Whether term positions are recorded in the `pos` column family for this field.
+    pub fn positional(&self) -> bool {
+        match *self {
+            Field::Subject | Field::Body => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the field holds addresses, indexed as lowercased `local@domain` and
+    /// `@domain` keywords next to the analyzed terms of their display names.
+    pub fn address(&self) -> bool {
+        match *self {
+            Field::From | Field::To | Field::Cc | Field::Bcc | Field::ReplyTo => true,
+            _ => false,
+        }
+    }
+
+    fn key(&self, term: &[u8]) -> Vec<u8> {
+        let prefix = self.prefix();
+        let mut key: Vec<u8> = Vec::with_capacity(prefix.len() + term.len());
+        key.extend(prefix.as_bytes());
+        key.extend(term);
+        key
+    }
+}
+
 impl DocId {
     fn parse(data: &[u8]) -> DocId {
         DocId(BigEndian::read_u32(data))
@@ -35,31 +376,86 @@ impl Iterator for StoreIt {
     type Item = (i64, DocIdSet);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let next = self.0.next();
-        if next.is_none() {
-            return None;
+        while !self.0.is_empty() {
+            let item = {
+                let negative = self.0[0].1;
+                self.0[0].0.next().and_then(|next| {
+                    if next.0.len() != "msg#date#".len() + 8 || &next.0[.."msg#date#".len()] != &b"msg#date#"[..] {
+                        return None;
+                    }
+                    let d = BigEndian::read_i64(&next.0["msg#date#".len()..]);
+                    // the segment ends where the sign bit flips
+                    if (d < 0) != negative {
+                        return None;
+                    }
+                    let docs: DocIdsMsg = DocIdsMsg::deserialize(&next.1);
+                    Some((d, docs.0))
+                })
+            };
+            if item.is_some() {
+                return item;
+            }
+            self.0.remove(0);
         }
+        None
+    }
+}
 
-        let next = next.unwrap();
-        if next.0.len() < "msg#date#".len() {
-            return None;
-        }
-        let f = &next.0[.."msg#date#".len()];
-        if f != &b"msg#date#"[..] {
-            return None;
+use std::sync::{Mutex, RwLock};
+use std::collections::{HashMap, HashSet};
+
+/// Number of values a `Sequence` reserves with each counter write.
+const SEQUENCE_BLOCK: u64 = 1000;
+
+/// Monotonic counter persisted by blocks. The end of a block is written before any
+/// value of the block is handed out, so the stored counter is always above every
+/// id used by committed data: a crash only leaves a gap, and messages no longer pay
+/// for a counter write of their own.
+struct Sequence {
+    cf: &'static str,
+    key: &'static [u8],
+    // next value, end of the reserved block
+    state: Mutex<(u64, u64)>,
+}
+
+impl Sequence {
+    fn open(db: &DB, cf: &'static str, key: &'static [u8]) -> Result<Sequence, StoreError> {
+        let handle = db.cf_handle(cf).unwrap();
+        // older stores kept the last value used, as a u32 for doc ids
+        let next = match db.get_cf(handle, key)? {
+            Some(x) => match x.len() {
+                4 => BigEndian::read_u32(x.deref()) as u64 + 1,
+                _ => BigEndian::read_u64(x.deref()) + 1,
+            },
+            None => 1,
+        };
+        Ok(Sequence {
+            cf: cf,
+            key: key,
+            state: Mutex::new((next, next)),
+        })
+    }
+
+    fn next(&self, db: &DB) -> Result<u64, StoreError> {
+        let mut state = self.state.lock().unwrap();
+        if state.0 >= state.1 {
+            let end = state.0 + SEQUENCE_BLOCK;
+            let mut data = vec![0; 8];
+            BigEndian::write_u64(&mut data[..], end);
+            db.put_cf(db.cf_handle(self.cf).unwrap(), self.key, &data[..])?;
+            state.1 = end;
         }
-        let date = &next.0["msg#date#".len()..];
-        let d = BigEndian::read_i64(date);
-        let docs: DocIdsMsg = DocIdsMsg::deserialize(&next.1);
+        let value = state.0;
+        state.0 += 1;
+        Ok(value)
+    }
 
-        Some((d, docs.0))
+    /// Last value handed out, 0 if none was.
+    fn last(&self) -> u64 {
+        self.state.lock().unwrap().0 - 1
     }
 }
 
-use std::sync::RwLock;
-use std::sync::atomic::{AtomicIsize, Ordering};
-use std::collections::HashMap;
-
 unsafe impl Send for Store {}
 
 pub struct Store {
@@ -68,26 +464,183 @@ pub struct Store {
     index_cf: ColumnFamily,
     eml_cf: ColumnFamily,
     mod_cf: ColumnFamily,*/
-    max_doc_id: AtomicIsize,
-    modseq_max: AtomicIsize,
+    doc_ids: Sequence,
+    modseqs: Sequence,
     cols: RwLock<(HashMap<u32, String>, HashMap<String, u32>)>,
+    // last UID given in each collection, held during every write assigning UIDs or
+    // logging changes, so that modseqs commit in the order they are allocated
+    uids: Mutex<HashMap<u32, u32>>,
+    // highest modseq of a committed write
+    committed: Mutex<u64>,
+    // whether the mod log holds entries of the older `mod#<modseq><col>` layout
+    legacy_mods: bool,
+    analyzers: HashMap<Field, Box<Analyzer>>,
+    headers: HashMap<String, HeaderIndex>,
+    header_analyzer: TextAnalyzer,
+    delimiter: char,
 }
 
-#[derive(PartialEq, Debug)]
+/// How the values of an indexed header are shredded under `msg#hdr#<name>#`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderIndex {
+    /// The whole value, trimmed and lowercased, as a single term.
+    Keyword,
+    /// The terms of the default text analyzer, without positions.
+    Text,
+}
+
+impl HeaderIndex {
+    fn name(&self) -> String {
+        match *self {
+            HeaderIndex::Keyword => "keyword".to_string(),
+            HeaderIndex::Text => format!("text:{}", TextAnalyzer::default().name()),
+        }
+    }
+}
+
+/// Settings applied when opening a `Store`.
+pub struct StoreConfig {
+    analyzers: HashMap<Field, Box<Analyzer>>,
+    headers: HashMap<String, HeaderIndex>,
+    delimiter: char,
+}
+
+impl StoreConfig {
+    /// Separator between the levels of a collection name, `/` by default.
+    pub fn delimiter(mut self, delimiter: char) -> StoreConfig {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Indexes the header `name`, matched case-insensitively, on top of the fields
+    /// every message is shredded into. Names are printable ASCII without `:` or `#`,
+    /// anything else fails `Store::open_with_config` with `InvalidArgument`.
+    pub fn header(mut self, name: &str, index: HeaderIndex) -> StoreConfig {
+        self.headers.insert(name.to_lowercase(), index);
+        self
+    }
+
+    /// Replaces the analyzer used to index and query a text field.
+    pub fn analyzer(mut self, field: Field, analyzer: Box<Analyzer>) -> StoreConfig {
+        self.analyzers.insert(field, analyzer);
+        self
+    }
+}
+
+impl Default for StoreConfig {
+    fn default() -> StoreConfig {
+        let mut analyzers: HashMap<Field, Box<Analyzer>> = HashMap::new();
+        analyzers.insert(Field::From, Box::new(TextAnalyzer::default()));
+        analyzers.insert(Field::To, Box::new(TextAnalyzer::default()));
+        analyzers.insert(Field::Cc, Box::new(TextAnalyzer::default()));
+        analyzers.insert(Field::Bcc, Box::new(TextAnalyzer::default()));
+        analyzers.insert(Field::ReplyTo, Box::new(TextAnalyzer::default()));
+        analyzers.insert(Field::Filename, Box::new(TextAnalyzer::default()));
+        analyzers.insert(Field::Subject, Box::new(TextAnalyzer::default()));
+        analyzers.insert(Field::Body, Box::new(TextAnalyzer::default()));
+        StoreConfig {
+            analyzers: analyzers,
+            headers: HashMap::new(),
+            delimiter: '/',
+        }
+    }
+}
+
+/// A node of the MIME tree of a message, as sketched by `MsgPart` in mailproto.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MsgPart {
+    pub headers: Vec<(String, String)>,
+    pub part_id: String,
+    pub mime_type: String,
+    /// Size of the decoded content, 0 for multiparts.
+    pub size: u64,
+    pub parts: Vec<MsgPart>,
+}
+
+impl MsgPart {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|h| h.0.eq_ignore_ascii_case(name))
+            .map(|h| h.1.as_str())
+    }
+
+    /// File name of the part, from Content-Disposition or the legacy `name` parameter
+    /// of Content-Type.
+    pub fn filename(&self) -> Option<String> {
+        self.header("Content-Disposition")
+            .and_then(|d| mime::param(d, "filename"))
+            .or_else(|| self.header("Content-Type").and_then(|t| mime::param(t, "name")))
+            .and_then(|f| if f.is_empty() { None } else { Some(f) })
+    }
+
+    /// Whether the part is an attachment rather than a body of the message: explicitly
+    /// disposed as one, or a named leaf part.
+    pub fn is_attachment(&self) -> bool {
+        if !self.parts.is_empty() {
+            return false;
+        }
+        let disposition = self.header("Content-Disposition").unwrap_or("");
+        disposition.trim().to_lowercase().starts_with("attachment") || self.filename().is_some()
+    }
+
+    /// Attachments of the tree rooted at this part, depth first.
+    pub fn attachments(&self) -> Vec<&MsgPart> {
+        let mut ret = vec![];
+        if self.is_attachment() {
+            ret.push(self);
+        }
+        for part in self.parts.iter() {
+            ret.extend(part.attachments());
+        }
+        ret
+    }
+}
+
+/// A mailbox: optional display name and email address.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Address(pub Option<String>, pub String);
 
+impl Address {
+    /// Lowercased domain of the address, without the `@`.
+    pub fn domain(&self) -> Option<String> {
+        match self.1.rfind('@') {
+            Some(at) if !self.1[at + 1..].trim().is_empty() => Some(self.1[at + 1..].trim().to_lowercase()),
+            _ => None,
+        }
+    }
+}
+
 #[derive(PartialEq, Debug)]
 pub struct Msg {
     pub subject: Option<String>,
-    pub from: Option<String>,
+    pub from: Vec<Address>,
+    pub to: Vec<Address>,
+    pub cc: Vec<Address>,
+    pub bcc: Vec<Address>,
+    pub reply_to: Vec<Address>,
     pub text: String,
     pub date: i64,
     pub eml: Vec<u8>,
+    /// Every header of the message, unfolded, in order.
+    pub headers: Vec<(String, String)>,
+    /// MIME tree, walked to index the attachments.
+    pub mime: Option<MsgPart>,
+    /// Message-ID, without its angle brackets (see `thread::parse_message_ids`).
+    pub message_id: Option<String>,
+    pub in_reply_to: Vec<String>,
+    pub references: Vec<String>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum StoreError {
     DbError(String),
+    TooManyTerms(usize),
+    AnalyzerMismatch(String),
+    DelimiterMismatch(String),
+    NotFound(u32),
+    DuplicateName(String),
+    InvalidArgument(String),
 }
 
 struct DocIdsMsg(RoaringBitmap, RoaringBitmap);
@@ -121,71 +674,156 @@ impl DocIdsMsg {
         self.1.serialize_into(&mut data).unwrap();
         data
     }
+}
+
+/// Positions of one term inside one field of one document, keyed by
+/// `msg#<field>#<term>\0<doc_id>` in the `pos` column family.
+struct Positions(Vec<u32>);
+
+impl Positions {
+    fn key(field: Field, term: &str, doc_id: &DocId) -> Vec<u8> {
+        let mut key = field.key(term.as_bytes());
+        key.push(0);
+        key.extend(&doc_id.write()[..]);
+        key
+    }
+
+    fn deserialize(data: &[u8]) -> Positions {
+        Positions(data.chunks(4).map(|c| BigEndian::read_u32(c)).collect())
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut data: Vec<u8> = vec![0; self.0.len() * 4];
+        for (i, p) in self.0.iter().enumerate() {
+            BigEndian::write_u32(&mut data[i * 4..], *p);
+        }
+        data
+    }
+
+    fn contains(&self, pos: u32) -> bool {
+        self.0.binary_search(&pos).is_ok()
+    }
+
+    fn within(&self, other: &Positions, distance: u32) -> bool {
+        // both lists are sorted, walk them together
+        let (mut i, mut j) = (0, 0);
+        while i < self.0.len() && j < other.0.len() {
+            let (a, b) = (self.0[i], other.0[j]);
+            if a.max(b) - a.min(b) <= distance {
+                return true;
+            }
+            if a < b {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        false
+    }
+}
+
+/// Every `index` key a document was shredded under, so that it can be removed from
+/// all of its posting lists. Collection keys are not recorded as they change after `put`.
+struct DocKeys(Vec<Vec<u8>>);
+
+impl DocKeys {
+    fn deserialize(data: &[u8]) -> DocKeys {
+        let mut keys = vec![];
+        let mut i = 0;
+        while i + 4 <= data.len() {
+            let len = BigEndian::read_u32(&data[i..i + 4]) as usize;
+            keys.push(data[i + 4..i + 4 + len].to_vec());
+            i += 4 + len;
+        }
+        DocKeys(keys)
+    }
 
-    fn one(doc_id: &DocId) -> DocIdsMsg {
-        let mut add = RoaringBitmap::default();
-        add.insert(doc_id.0);
-        DocIdsMsg(add, RoaringBitmap::default())
+    fn serialize(&self) -> Vec<u8> {
+        let mut data: Vec<u8> = Vec::with_capacity(self.0.iter().map(|k| k.len() + 4).sum());
+        for key in self.0.iter() {
+            let mut len: Vec<u8> = vec![0; 4];
+            BigEndian::write_u32(&mut len[..], key.len() as u32);
+            data.extend(len);
+            data.extend(key);
+        }
+        data
     }
 }
 
-pub type DocIds = Vec<u32>;
+/// Postings aggregated per `index` key, so that a batch of messages writes a single
+/// merge operand per key instead of one per message and token.
+struct Postings(HashMap<Vec<u8>, DocIdSet>);
 
+impl Postings {
+    fn add(&mut self, key: Vec<u8>, doc_id: &DocId) {
+        self.0.entry(key).or_insert_with(DocIdSet::default).insert(doc_id.0);
+    }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum StoreError {
-    DbError(String),
+    fn write(self, db: &DB, batch: &mut WriteBatch) -> Result<(), StoreError> {
+        for (key, docs) in self.0 {
+            batch.merge_cf(
+                db.cf_handle("index").unwrap(),
+                &key[..],
+                &DocIdsMsg(docs, RoaringBitmap::default()).serialize()[..],
+            )?;
+        }
+        Ok(())
+    }
 }
 
+pub type DocIds = Vec<u32>;
+
 impl From<rocksdb::Error> for StoreError {
     fn from(e: Error) -> StoreError {
         StoreError::DbError(e.into())
     }
 }
 
-fn concat_merge(_new_key: &[u8], existing_val: Option<&[u8]>, operands: &mut MergeOperands) -> Option<Vec<u8>> {
-    use std::time::Instant;
-    let now = Instant::now();
+/// Folds merge operands, in order, into `base`. The remove set of the result is only
+/// kept for partial merges, where there is no base value yet: it still has to be
+/// applied to the older value the result will be merged onto.
+fn merge_docs(base: Option<DocIdsMsg>, ops: Vec<DocIdsMsg>) -> DocIdsMsg {
+    let partial = base.is_none();
+    let mut ret = match base {
+        Some(mut docs) => {
+            docs.0.difference_with(&docs.1);
+            DocIdsMsg(docs.0, RoaringBitmap::default())
+        }
+        None => DocIdsMsg(RoaringBitmap::default(), RoaringBitmap::default()),
+    };
+    // operands are applied in order: a message can leave a collection and be added
+    // back to it before the key is compacted
+    for op in ops {
+        ret.0.difference_with(&op.1);
+        ret.0.union_with(&op.0);
+        ret.1.difference_with(&op.0);
+        ret.1.union_with(&op.1);
+    }
+    if !partial {
+        ret.1 = RoaringBitmap::default();
+    }
+    ret
+}
 
-    let mut add: Vec<RoaringBitmap> = Vec::new();
-    let mut remove: Vec<RoaringBitmap> = Vec::new();
+fn concat_merge(_new_key: &[u8], existing_val: Option<&[u8]>, operands: &mut MergeOperands) -> Option<Vec<u8>> {
     let ops: Vec<&[u8]> = operands.collect();
     if let Some(existing_val) = existing_val {
         if ops.len() == 0 {
             return Some(existing_val.into());
         }
-        let docs = DocIdsMsg::deserialize(existing_val);
-        add.push(docs.0);
-        remove.push(docs.1);
-    }
-
-    if ops.len() == 1 && add.len() == 0 && remove.len() == 0 {
+    } else if ops.len() == 1 {
         return Some(ops[0].into());
     }
 
-    for op in ops.into_iter() {
-        let docs = DocIdsMsg::deserialize(op);
-        add.push(docs.0);
-        remove.push(docs.1)
-    }
-
-    let elapsed = now.elapsed();
-    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1000_000.0);
-    // println!("merge {}ms", sec);
-
-    let mut ret = RoaringBitmap::default();
-    for a in add {
-        ret.union_with(&a);
-    }
-    for r in remove {
-        ret.difference_with(&r);
-    }
-
-    let sr = DocIdsMsg(ret, RoaringBitmap::default()).serialize();
-    Some(sr)
+    let base = existing_val.map(DocIdsMsg::deserialize);
+    let ops = ops.into_iter().map(DocIdsMsg::deserialize).collect();
+    Some(merge_docs(base, ops).serialize())
 }
 
-pub struct StoreIt(rocksdb::DBIterator);
+/// Walk of the date index. Dates are keyed as big-endian `i64`, where negative dates
+/// sort after positive ones, so the walk is made of one segment per sign, each with
+/// whether it holds negative dates.
+pub struct StoreIt(Vec<(rocksdb::DBIterator, bool)>);
 
 use std::fmt::{Debug, Formatter, Result as FmtResult};
 impl Debug for Store {
@@ -230,6 +868,10 @@ impl Store {
     }
 
     pub fn open(path: &str) -> Result<Store, StoreError> {
+        Store::open_with_config(path, StoreConfig::default())
+    }
+
+    pub fn open_with_config(path: &str, config: StoreConfig) -> Result<Store, StoreError> {
         let mut gopts = Options::default();
 
         gopts.set_merge_operator("test", concat_merge, None);
@@ -245,24 +887,20 @@ impl Store {
         let eml_cf = ColumnFamilyDescriptor::new("eml", Store::eml_options());
 
         let mod_cf = ColumnFamilyDescriptor::new("mod", Store::index_options());
+        let pos_cf = ColumnFamilyDescriptor::new("pos", Store::index_options());
+        let doc_cf = ColumnFamilyDescriptor::new("doc", Store::index_options());
+        let thread_cf = ColumnFamilyDescriptor::new("thread", Store::index_options());
         let db = DB::open_cf_descriptors(
             &gopts,
             path,
-            vec![default_cf, index_cf, col_cf, mod_cf, eml_cf],
+            vec![default_cf, index_cf, col_cf, mod_cf, eml_cf, pos_cf, doc_cf, thread_cf],
         )?;
-        let mod_cf = match db.cf_handle("mod") {
-            Some(i) => i,
-            None => panic!(""),
-        };
-        let max = match db.get(b"max_doc_id")? {
-            Some(x) => DocId::parse(x.deref()),
-            None => DocId(1),
-        };
+        let doc_ids = Sequence::open(&db, "default", b"max_doc_id")?;
+        let modseqs = Sequence::open(&db, "mod", b"modseq_max")?;
 
-        let modseq_max = match db.get_cf(mod_cf, b"modseq_max")? {
-            Some(x) => BigEndian::read_u64(x.deref()),
-            None => 1,
-        };
+        Store::check_analyzers(&db, &config)?;
+        Store::check_headers(&db, &config)?;
+        Store::check_delimiter(&db, &config)?;
 
         let cols = Store::collections_internal(&db)?;
         let mut id_name = HashMap::new();
@@ -271,7 +909,12 @@ impl Store {
             id_name.insert(col.0, col.1.clone());
             name_id.insert(col.1.clone(), col.0);
         }
-        println!("max value {}", max.0);
+        let committed = modseqs.last();
+        let legacy_mods = {
+            use rocksdb::DBIterator;
+            let mut it: DBIterator = db.prefix_iterator_cf(db.cf_handle("mod").unwrap(), b"mod#")?;
+            it.next().map(|(k, _)| k.starts_with(b"mod#")).unwrap_or(false)
+        };
         Ok(Store {
             /*
             index_cf: match db.cf_handle("index") {
@@ -284,276 +927,2349 @@ impl Store {
                 None => panic!(""),
             },*/
             db: db,
-            max_doc_id: AtomicIsize::new(max.0 as isize),
-            modseq_max: AtomicIsize::new(modseq_max as isize),
+            doc_ids: doc_ids,
+            modseqs: modseqs,
             cols: RwLock::new((id_name, name_id)),
+            uids: Mutex::new(HashMap::new()),
+            committed: Mutex::new(committed),
+            legacy_mods: legacy_mods,
+            analyzers: config.analyzers,
+            headers: config.headers,
+            header_analyzer: TextAnalyzer::default(),
+            delimiter: config.delimiter,
         })
     }
 
-    fn next_modseq(&self) -> Result<u64, StoreError> {
-        let max = self.modseq_max.fetch_add(1, Ordering::SeqCst) as u64;
-        let mut data = vec![0; 8];
-        BigEndian::write_u64(&mut data[..], max);
-
-        ;
-        self.db
-            .put_cf(self.db.cf_handle("mod").unwrap(), b"modseq_max", &data[..])?;
-        Ok(max)
+    /// Records the analyzer of every text field on first open, and refuses to open
+    /// the store with a different analysis afterwards.
+    fn check_analyzers(db: &DB, config: &StoreConfig) -> Result<(), StoreError> {
+        for (field, analyzer) in config.analyzers.iter() {
+            let key = format!("analyzer#{}", field.name());
+            let name = analyzer.name();
+            match db.get(key.as_bytes())? {
+                Some(stored) => {
+                    if stored.deref() != name.as_bytes() {
+                        return Err(StoreError::AnalyzerMismatch(format!(
+                            "{} indexed with {}, configured with {}",
+                            field.name(),
+                            String::from_utf8_lossy(stored.deref()),
+                            name
+                        )));
+                    }
+                }
+                None => db.put(key.as_bytes(), name.as_bytes())?,
+            }
+        }
+        Ok(())
     }
 
-    fn next_doc(&self) -> Result<DocId, StoreError> {
-        let max = DocId(self.max_doc_id.fetch_add(1, Ordering::SeqCst) as u32);
-
-        self.db.put(b"max_doc_id", &max.write()[..])?;
-        Ok(max)
+    /// Same as `check_analyzers` for indexed headers: a header keeps the way it was
+    /// first indexed with. Headers can be added to the configuration at any time, but
+    /// only messages stored afterwards are indexed under them.
+    fn check_headers(db: &DB, config: &StoreConfig) -> Result<(), StoreError> {
+        for (header, index) in config.headers.iter() {
+            // `#` separates the name from the value in the index keys
+            if header.is_empty() || !header.chars().all(|c| c.is_ascii_graphic() && c != ':' && c != '#') {
+                return Err(StoreError::InvalidArgument(format!("invalid header name {:?}", header)));
+            }
+            let key = format!("header#{}", header);
+            let name = index.name();
+            match db.get(key.as_bytes())? {
+                Some(stored) => {
+                    if stored.deref() != name.as_bytes() {
+                        return Err(StoreError::AnalyzerMismatch(format!(
+                            "header {} indexed as {}, configured as {}",
+                            header,
+                            String::from_utf8_lossy(stored.deref()),
+                            name
+                        )));
+                    }
+                }
+                None => db.put(key.as_bytes(), name.as_bytes())?,
+            }
+        }
+        Ok(())
     }
 
-    fn shred_text(&self, batch: &mut WriteBatch, doc_id: &DocId, name: &str, value: &str) -> Result<(), StoreError> {
-        let base_key = format!("msg#{}#", name);
-        for s in value.unicode_words() {
-            let mut key: Vec<u8> = Vec::with_capacity(base_key.len() + s.len());
-            key.extend(base_key.as_bytes());
-            key.extend(s.as_bytes());
-            batch.merge_cf(
-                self.db.cf_handle("index").unwrap(),
-                &key[..],
-                &DocIdsMsg::one(doc_id).serialize()[..],
-            )?;
+    /// Records the hierarchy delimiter on first open: collection names were split with
+    /// it, so the store cannot be opened with another one.
+    fn check_delimiter(db: &DB, config: &StoreConfig) -> Result<(), StoreError> {
+        let mut delimiter = vec![0; config.delimiter.len_utf8()];
+        config.delimiter.encode_utf8(&mut delimiter);
+        match db.get(b"delimiter")? {
+            Some(stored) => {
+                if stored.deref() != &delimiter[..] {
+                    return Err(StoreError::DelimiterMismatch(format!(
+                        "collections named with {}, configured with {}",
+                        String::from_utf8_lossy(stored.deref()),
+                        config.delimiter
+                    )));
+                }
+            }
+            None => db.put(b"delimiter", &delimiter[..])?,
         }
         Ok(())
     }
 
-    fn shred_string(&self, batch: &mut WriteBatch, doc_id: &DocId, name: &str, value: &str) -> Result<(), StoreError> {
-        let base_key = format!("msg#{}#", name);
-        let mut key: Vec<u8> = Vec::with_capacity(base_key.len() + value.len());
-        key.extend(base_key.as_bytes());
-        key.extend(value.as_bytes());
-        batch.merge_cf(
-            self.db.cf_handle("index").unwrap(),
-            &key[..],
-            &DocIdsMsg::one(doc_id).serialize()[..],
-        )?;
-
-        Ok(())
+    fn next_modseq(&self) -> Result<u64, StoreError> {
+        self.modseqs.next(&self.db)
     }
 
-    fn shred_date(&self, batch: &mut WriteBatch, doc_id: &DocId, name: &str, value: i64) -> Result<(), StoreError> {
-        let base_key = format!("msg#{}#", name);
-        let mut key: Vec<u8> = Vec::with_capacity(base_key.len() + 8);
-        key.extend(base_key.as_bytes());
+    fn next_doc(&self) -> Result<DocId, StoreError> {
+        Ok(DocId(self.doc_ids.next(&self.db)? as u32))
+    }
 
-        let mut v: Vec<u8> = vec![0; 8];
-        BigEndian::write_i64(&mut v, value);
-        key.extend(&v[..]);
-        batch.merge_cf(
-            self.db.cf_handle("index").unwrap(),
-            &key[..],
-            &DocIdsMsg::one(doc_id).serialize()[..],
-        )?;
+    fn index_key(&self, postings: &mut Postings, keys: &mut DocKeys, doc_id: &DocId, key: Vec<u8>) {
+        keys.0.push(key.clone());
+        postings.add(key, doc_id);
+    }
 
+    fn shred_text(
+        &self,
+        batch: &mut WriteBatch,
+        postings: &mut Postings,
+        keys: &mut DocKeys,
+        doc_id: &DocId,
+        field: Field,
+        value: &str,
+    ) -> Result<(), StoreError> {
+        let mut terms: HashMap<String, Vec<u32>> = HashMap::new();
+        for token in self.analyzers[&field].analyze(value) {
+            terms.entry(token.text).or_insert_with(Vec::new).push(token.position);
+        }
+        for (s, positions) in terms {
+            self.index_key(postings, keys, doc_id, field.key(s.as_bytes()));
+            if field.positional() {
+                batch.put_cf(
+                    self.db.cf_handle("pos").unwrap(),
+                    &Positions::key(field, &s, doc_id)[..],
+                    &Positions(positions).serialize()[..],
+                )?;
+            }
+        }
         Ok(())
     }
 
-    fn shred_collections(&self, batch: &mut WriteBatch, doc_id: &DocId, collections: &Vec<u32>) -> Result<(), StoreError> {
-        let base_key = "msg#cols#";
-        for col in collections {
-            let mut key: Vec<u8> = Vec::with_capacity(base_key.len() + 4);
-            key.extend(base_key.as_bytes());
+    fn shred_string(&self, postings: &mut Postings, keys: &mut DocKeys, doc_id: &DocId, field: Field, value: &str) {
+        self.index_key(postings, keys, doc_id, field.key(value.as_bytes()))
+    }
+
+    fn shred_addresses(
+        &self,
+        batch: &mut WriteBatch,
+        postings: &mut Postings,
+        keys: &mut DocKeys,
+        doc_id: &DocId,
+        field: Field,
+        addresses: &[Address],
+    ) -> Result<(), StoreError> {
+        let mut terms = HashSet::new();
+        let mut names = vec![];
+        for address in addresses {
+            let email = address.1.trim().to_lowercase();
+            if !email.is_empty() {
+                terms.insert(email);
+            }
+            if let Some(domain) = address.domain() {
+                terms.insert(format!("@{}", domain));
+            }
+            if let Some(ref name) = address.0 {
+                names.push(name.as_str());
+            }
+        }
+        for term in terms {
+            self.index_key(postings, keys, doc_id, field.key(term.as_bytes()));
+        }
+        if !names.is_empty() {
+            self.shred_text(batch, postings, keys, doc_id, field, &names.join("\n"))?;
+        }
+        Ok(())
+    }
+
+    fn header_key(header: &str, term: &str) -> Vec<u8> {
+        format!("msg#hdr#{}#{}", header, term).into_bytes()
+    }
+
+    fn shred_headers(&self, postings: &mut Postings, keys: &mut DocKeys, doc_id: &DocId, headers: &[(String, String)]) {
+        let mut terms = HashSet::new();
+        for &(ref name, ref value) in headers {
+            let name = name.trim().to_lowercase();
+            match self.headers.get(&name) {
+                Some(&HeaderIndex::Keyword) => {
+                    terms.insert(Store::header_key(&name, &value.trim().to_lowercase()));
+                }
+                Some(&HeaderIndex::Text) => for token in self.header_analyzer.analyze(value) {
+                    terms.insert(Store::header_key(&name, &token.text));
+                },
+                None => {}
+            }
+        }
+        for key in terms {
+            self.index_key(postings, keys, doc_id, key);
+        }
+    }
+
+    /// Indexes the file names, lowercased MIME types and sizes of the attachments, and
+    /// the message under `has:attachment` if there is any.
+    fn shred_attachments(
+        &self,
+        batch: &mut WriteBatch,
+        postings: &mut Postings,
+        keys: &mut DocKeys,
+        doc_id: &DocId,
+        mime: &MsgPart,
+    ) -> Result<(), StoreError> {
+        let attachments = mime.attachments();
+        if attachments.is_empty() {
+            return Ok(());
+        }
+        self.index_key(postings, keys, doc_id, Field::Has.key(b"attachment"));
+
+        let mut terms = HashSet::new();
+        let mut names = vec![];
+        for part in attachments {
+            if let Some(filename) = part.filename() {
+                terms.insert(Field::Filename.key(filename.to_lowercase().as_bytes()));
+                // split on extension dots and separators, so that `pdf` matches `report.pdf`
+                names.push(filename.replace(|c: char| c == '.' || c == '_' || c == '-', " "));
+            }
+            let mime_type = part.mime_type.trim().to_lowercase();
+            if !mime_type.is_empty() {
+                if let Some(slash) = mime_type.find('/') {
+                    terms.insert(Field::MimeType.key(mime_type[..slash].as_bytes()));
+                }
+                terms.insert(Field::MimeType.key(mime_type.as_bytes()));
+            }
+            let mut v: Vec<u8> = vec![0; 8];
+            BigEndian::write_u64(&mut v, part.size);
+            terms.insert(Field::Size.key(&v[..]));
+        }
+        for key in terms {
+            self.index_key(postings, keys, doc_id, key);
+        }
+        if !names.is_empty() {
+            self.shred_text(batch, postings, keys, doc_id, Field::Filename, &names.join("\n"))?;
+        }
+        Ok(())
+    }
+
+    fn shred_date(&self, postings: &mut Postings, keys: &mut DocKeys, doc_id: &DocId, field: Field, value: i64) {
+        let mut v: Vec<u8> = vec![0; 8];
+        BigEndian::write_i64(&mut v, value);
+        self.index_key(postings, keys, doc_id, field.key(&v[..]))
+    }
 
+    fn shred_collections(&self, postings: &mut Postings, doc_id: &DocId, collections: &Vec<u32>) {
+        for col in collections {
             let mut v: Vec<u8> = vec![0; 4];
             BigEndian::write_u32(&mut v, *col);
-            key.extend(&v[..]);
-            batch.merge_cf(
-                self.db.cf_handle("index").unwrap(),
-                &key[..],
-                &DocIdsMsg::one(doc_id).serialize()[..],
-            )?;
+            postings.add(Field::Cols.key(&v[..]), doc_id);
         }
-        Ok(())
     }
 
-    fn shred(&self, batch: &mut WriteBatch, doc_id: &DocId, msg: &Msg) -> Result<(), StoreError> {
-        let from = msg.from.as_ref();
-        if let Some(from) = from {
-            self.shred_text(batch, doc_id, "from", from)?;
+    fn shred(&self, batch: &mut WriteBatch, postings: &mut Postings, doc_id: &DocId, thread_id: u32, msg: &Msg) -> Result<(), StoreError> {
+        let mut keys = DocKeys(vec![]);
+        let mut v: Vec<u8> = vec![0; 4];
+        BigEndian::write_u32(&mut v, thread_id);
+        self.index_key(postings, &mut keys, doc_id, Field::Thread.key(&v[..]));
+        self.shred_addresses(batch, postings, &mut keys, doc_id, Field::From, &msg.from)?;
+        self.shred_addresses(batch, postings, &mut keys, doc_id, Field::To, &msg.to)?;
+        self.shred_addresses(batch, postings, &mut keys, doc_id, Field::Cc, &msg.cc)?;
+        self.shred_addresses(batch, postings, &mut keys, doc_id, Field::Bcc, &msg.bcc)?;
+        self.shred_addresses(batch, postings, &mut keys, doc_id, Field::ReplyTo, &msg.reply_to)?;
+        self.shred_headers(postings, &mut keys, doc_id, &msg.headers);
+        if let Some(ref mime) = msg.mime {
+            self.shred_attachments(batch, postings, &mut keys, doc_id, mime)?;
         }
 
-        self.shred_text(batch, doc_id, "body", &msg.text)?;
+        self.shred_text(batch, postings, &mut keys, doc_id, Field::Body, &msg.text)?;
 
-        self.shred_date(batch, doc_id, "date", msg.date)?;
+        self.shred_date(postings, &mut keys, doc_id, Field::Date, msg.date);
         let subject = msg.subject.as_ref();
         if let Some(subject) = subject {
-            self.shred_text(batch, doc_id, "subject", subject)
-        } else {
-            Ok(())
+            self.shred_text(batch, postings, &mut keys, doc_id, Field::Subject, subject)?;
         }
-    }
 
-    pub fn modify(&self, msgs: Vec<u8>, added_collections: Vec<u32>, removed_collections: Vec<u32>) -> Result<(), StoreError> {
-        for msg in msgs {}
+        batch.put_cf(self.db.cf_handle("doc").unwrap(), &doc_id.write()[..], &keys.serialize()[..])?;
         Ok(())
     }
 
-    fn next_col_id(&self, col: u32) -> Result<u32, StoreError> {
-        let mut key = Vec::new();
-        key.extend(b"col_seq#".iter());
-        let mut v: Vec<u8> = vec![0; 4];
-        BigEndian::write_u32(&mut v, col);
-        key.extend(&v[..]);
-        let value = self.db.get_cf(self.db.cf_handle("col").unwrap(),&key[..])?;
-        let next_col_id = if let Some(value) = value {
-            BigEndian::read_u32(&value) + 1
-        } else {
-            1
-        };
-        Ok(next_col_id)
+    /// Index keys of a document, `None` for unknown documents and for the ones stored
+    /// before keys were recorded.
+    fn doc_keys(&self, doc_id: u32) -> Result<Option<DocKeys>, StoreError> {
+        let res = self.db
+            .get_cf(self.db.cf_handle("doc").unwrap(), &DocId(doc_id).write()[..])?;
+        Ok(res.map(|keys| DocKeys::deserialize(keys.deref())))
     }
 
-    pub fn put(&self, collections: &Vec<u32>, msg: &Msg) -> Result<(), StoreError> {
-        let doc_id = self.next_doc()?;
+    /// Index keys holding any of `doc_ids`, found by scanning every message key. Only
+    /// used for documents without a `doc` record.
+    fn scan_doc_keys(&self, doc_ids: &DocIdSet) -> Result<Vec<(Vec<u8>, DocIdSet)>, StoreError> {
+        use rocksdb::DBIterator;
+        let it: DBIterator = self.db
+            .prefix_iterator_cf(self.db.cf_handle("index").unwrap(), b"msg#")?;
+        let mut ret = vec![];
+        for (key, value) in it {
+            if !key.starts_with(b"msg#") {
+                break;
+            }
+            let mut docs = DocIdsMsg::deserialize(&value).0;
+            docs.intersect_with(doc_ids);
+            if !docs.is_empty() {
+                ret.push((key.to_vec(), docs));
+            }
+        }
+        Ok(ret)
+    }
 
+    /// Removes messages from every posting list, collection and positional record
+    /// they were indexed under, and drops their raw bytes. Nothing is written if one
+    /// of `doc_ids` does not exist.
+    pub fn delete(&self, doc_ids: &DocIdSet) -> Result<(), StoreError> {
+        let _uids = self.uids.lock().unwrap();
         let mut batch = WriteBatch::default();
+        self.delete_into(&mut batch, doc_ids)?;
+        self.commit(batch)?;
+        Ok(())
+    }
 
-        // mod log
-        let base_mod_key = "mod#";
-        for col in collections {
-            let col_id = self.next_col_id(*col)?;
-            let modseq = self.next_modseq()?;
-            let mut key: Vec<u8> = Vec::with_capacity(base_mod_key.len() + 8);
-            key.extend(base_mod_key.as_bytes());
-
-            let mut v: Vec<u8> = vec![0; 8];
-            BigEndian::write_u64(&mut v, modseq);
-            key.extend(&v[..]);
+    fn delete_into(&self, batch: &mut WriteBatch, doc_ids: &DocIdSet) -> Result<(), StoreError> {
+        let mut removals: HashMap<Vec<u8>, DocIdSet> = HashMap::new();
+        let mut positions = vec![];
+        let mut legacy = DocIdSet::default();
+        let mut indexed = vec![];
+        for doc_id in doc_ids.iter() {
+            match self.doc_keys(doc_id)? {
+                Some(keys) => for key in keys.0 {
+                    indexed.push((key, doc_id));
+                },
+                None => {
+                    self.check_doc(doc_id)?;
+                    legacy.insert(doc_id);
+                }
+            }
+        }
+        if !legacy.is_empty() {
+            for (key, docs) in self.scan_doc_keys(&legacy)? {
+                for doc_id in docs.iter() {
+                    indexed.push((key.clone(), doc_id));
+                }
+            }
+        }
+        for (key, doc_id) in indexed {
+            if key.starts_with(Field::Subject.prefix().as_bytes()) || key.starts_with(Field::Body.prefix().as_bytes()) {
+                let mut pos_key = key.clone();
+                pos_key.push(0);
+                pos_key.extend(&DocId(doc_id).write()[..]);
+                positions.push(pos_key);
+            }
+            removals.entry(key).or_insert_with(DocIdSet::default).insert(doc_id);
+        }
 
+        for col in self.collections()? {
             let mut v: Vec<u8> = vec![0; 4];
-            BigEndian::write_u32(&mut v, *col);
-            key.extend(&v[..]);
-            batch.put_cf(self.db.cf_handle("mod").unwrap(), &key[..], b"add")?;
+            BigEndian::write_u32(&mut v, col.0);
+            let key = Field::Cols.key(&v[..]);
+            let mut removed = self.get_docs(&key[..])?.unwrap_or_default();
+            removed.intersect_with(doc_ids);
+            if removed.is_empty() {
+                continue;
+            }
+            self.log_change(batch, col.0, &Change::Remove(removed.clone()))?;
+            self.remove_uids(batch, col.0, &removed)?;
+            removals.insert(key, removed);
         }
-        self.shred_collections(&mut batch, &doc_id, collections)?;
-        self.shred(&mut batch, &doc_id, msg)?;
 
-        {
-            let base_eml_key = "eml#";
-            let mut key: Vec<u8> = Vec::with_capacity(base_eml_key.len() + 4);
-            key.extend(&doc_id.write()[..]);
-            batch.put_cf(self.db.cf_handle("eml").unwrap(), &key[..], &msg.eml[..])?;
+        for key in self.keys_with_prefix("index", Field::Flag.prefix().as_bytes())? {
+            removals.insert(key, doc_ids.clone());
+        }
+
+        for (key, removed) in removals {
+            batch.merge_cf(
+                self.db.cf_handle("index").unwrap(),
+                &key[..],
+                &DocIdsMsg(DocIdSet::default(), removed).serialize()[..],
+            )?;
+        }
+        for key in positions {
+            batch.delete_cf(self.db.cf_handle("pos").unwrap(), &key[..])?;
+        }
+        for doc_id in doc_ids.iter() {
+            let key = DocId(doc_id).write();
+            batch.delete_cf(self.db.cf_handle("eml").unwrap(), &key[..])?;
+            batch.delete_cf(self.db.cf_handle("doc").unwrap(), &key[..])?;
         }
-        self.db.write(batch)?;
         Ok(())
     }
 
-    pub fn compact(&self) {
-        self.db
-            .compact_range_cf(self.db.cf_handle("index").unwrap(), None, None);
+    fn keys_with_prefix(&self, cf: &str, prefix: &[u8]) -> Result<Vec<Vec<u8>>, StoreError> {
+        let mut ret = vec![];
+        use rocksdb::DBIterator;
+        let it: DBIterator = self.db
+            .prefix_iterator_cf(self.db.cf_handle(cf).unwrap(), prefix)?;
+        for (k, _) in it {
+            if k.len() < prefix.len() || &k[..prefix.len()] != prefix {
+                break;
+            }
+            ret.push(k.to_vec());
+        }
+        Ok(ret)
     }
 
-    pub fn iterate_date(&self) -> Result<StoreIt, StoreError> {
-        let mut key = Vec::new();
-        key.extend(b"msg#date#".iter());
+    /// Sets `flags` on `msgs`.
+    pub fn set_flags(&self, msgs: &DocIdSet, flags: &[Flag]) -> Result<(), StoreError> {
+        self.change_flags(msgs, flags, true)
+    }
 
-        use rocksdb::DBIterator;
-        let it: DBIterator = self.db
-            .prefix_iterator_cf(self.db.cf_handle("index").unwrap(), &key[..])?;
-        Ok(StoreIt(it))
+    /// Clears `flags` from `msgs`.
+    pub fn clear_flags(&self, msgs: &DocIdSet, flags: &[Flag]) -> Result<(), StoreError> {
+        self.change_flags(msgs, flags, false)
     }
 
-    pub fn create_collection(&self, name: String) -> Result<Collection, StoreError> {
-        let doc_id = self.next_doc()?;
-        let mut key = Vec::new();
-        key.extend(b"collections#".iter());
+    fn change_flags(&self, msgs: &DocIdSet, flags: &[Flag], set: bool) -> Result<(), StoreError> {
+        for flag in flags {
+            flag.validate()?;
+        }
+        if msgs.is_empty() || flags.is_empty() {
+            return Ok(());
+        }
 
+        let _uids = self.uids.lock().unwrap();
         let mut batch = WriteBatch::default();
+        let change = if set {
+            DocIdsMsg(msgs.clone(), DocIdSet::default())
+        } else {
+            DocIdsMsg(DocIdSet::default(), msgs.clone())
+        };
+        for flag in flags {
+            batch.merge_cf(self.db.cf_handle("index").unwrap(), &flag.key()[..], &change.serialize()[..])?;
+        }
 
-        let v = doc_id.write();
-        key.extend(&v[..]);
+        // flags are global to a message, the change is logged in every collection
+        // holding one of the messages
+        for col in self.collections()? {
+            let mut v: Vec<u8> = vec![0; 4];
+            BigEndian::write_u32(&mut v, col.0);
+            let mut changed = self.get_docs(&Field::Cols.key(&v[..])[..])?.unwrap_or_default();
+            changed.intersect_with(msgs);
+            if changed.is_empty() {
+                continue;
+            }
+            self.log_change(&mut batch, col.0, &Change::Flags(changed))?;
+        }
+        self.commit(batch)?;
+        Ok(())
+    }
+
+    pub fn find_by_flag(&self, flag: &Flag) -> Result<Option<DocIdSet>, StoreError> {
+        flag.validate()?;
+        self.get_docs(&flag.key()[..])
+    }
+
+    /// Writes a batch holding logged changes. Must be called with the `uids` lock held
+    /// since the modseqs of the batch were allocated.
+    fn commit(&self, batch: WriteBatch) -> Result<(), StoreError> {
+        self.db.write(batch)?;
+        *self.committed.lock().unwrap() = self.modseqs.last();
+        Ok(())
+    }
 
+    fn log_change(&self, batch: &mut WriteBatch, col: u32, change: &Change) -> Result<(), StoreError> {
+        let modseq = self.next_modseq()?;
         batch.put_cf(
-            self.db.cf_handle("col").unwrap(),
-            &key[..],
-            &name.as_bytes(),
+            self.db.cf_handle("mod").unwrap(),
+            &Store::mod_key(modseq, col)[..],
+            &change.serialize()[..],
         )?;
-        self.db.write(batch)?;
-        Ok(Collection(doc_id.0, name))
+        Ok(())
     }
 
-    fn collections_internal(db: &DB) -> Result<Vec<Collection>, StoreError> {
-        let mut key = Vec::new();
-        key.extend(b"collections#".iter());
+    /// Changes logged for `col` with a modseq strictly greater than `modseq`.
+    pub fn changes_since(&self, modseq: u64, col: u32) -> Result<Changes, StoreError> {
+        let high = *self.committed.lock().unwrap();
+        let mut ret = Changes {
+            changes: vec![],
+            modseq: high,
+        };
+        if modseq >= high {
+            return Ok(ret);
+        }
 
-        let mut ret = vec![];
-        use rocksdb::DBIterator;
-        let it: DBIterator = db.prefix_iterator_cf(db.cf_handle("col").unwrap(), &key[..])?;
-        for v in it {
-            let k = v.0;
-            if k.len() < b"collections#".len() || &k[0..b"collections#".len()] != b"collections#" {
+        let prefix = Store::mod_key(0, col);
+        let prefix = &prefix[..prefix.len() - 8];
+        let start = Store::mod_key(modseq + 1, col);
+        use rocksdb::{DBIterator, Direction, IteratorMode};
+        let it: DBIterator = self.db.iterator_cf(
+            self.db.cf_handle("mod").unwrap(),
+            IteratorMode::From(&start[..], Direction::Forward),
+        )?;
+        for (k, v) in it {
+            if k.len() != prefix.len() + 8 || &k[..prefix.len()] != prefix {
+                break;
+            }
+            let change_modseq = BigEndian::read_u64(&k[prefix.len()..]);
+            // written after `high` was read, returned by the next call
+            if change_modseq > high {
                 break;
             }
+            if let Some(change) = Change::deserialize(&v) {
+                ret.changes.push((change_modseq, change));
+            }
+        }
 
-            ret.push(Collection(
-                DocId::parse(&k[b"collections#".len()..]).0,
-                str::from_utf8(&v.1).unwrap().to_string(),
-            ))
+        if self.legacy_mods {
+            ret.changes.extend(self.legacy_changes_since(modseq, col, high)?);
+            ret.changes.sort_by_key(|c| c.0);
         }
         Ok(ret)
     }
 
-    pub fn collections(&self) -> Result<Vec<Collection>, StoreError> {
-        Store::collections_internal(&self.db)
+    /// Changes logged under the older `mod#<modseq><col>` layout, which interleaves
+    /// every collection.
+    fn legacy_changes_since(&self, modseq: u64, col: u32, high: u64) -> Result<Vec<(u64, Change)>, StoreError> {
+        let base_mod_key = b"mod#";
+        let mut start = base_mod_key.to_vec();
+        let mut v: Vec<u8> = vec![0; 12];
+        BigEndian::write_u64(&mut v[..8], modseq + 1);
+        start.extend(&v[..]);
+        use rocksdb::{DBIterator, Direction, IteratorMode};
+        let it: DBIterator = self.db.iterator_cf(
+            self.db.cf_handle("mod").unwrap(),
+            IteratorMode::From(&start[..], Direction::Forward),
+        )?;
+        let mut ret = vec![];
+        for (k, v) in it {
+            if k.len() != base_mod_key.len() + 8 + 4 || &k[..base_mod_key.len()] != base_mod_key {
+                break;
+            }
+            let k = &k[base_mod_key.len()..];
+            let change_modseq = BigEndian::read_u64(&k[..8]);
+            if change_modseq > high {
+                break;
+            }
+            if BigEndian::read_u32(&k[8..]) != col {
+                continue;
+            }
+            if let Some(change) = Change::deserialize(&v) {
+                ret.push((change_modseq, change));
+            }
+        }
+        Ok(ret)
     }
 
-    pub fn find_by_name(&self, name: &str) -> Result<Option<DocIdSet>, StoreError> {
-        let mut key = Vec::new();
-        key.extend(b"msg#body#".iter());
-        key.extend(name.as_bytes());
-        use std::time::Instant;
-        let now = Instant::now();
+    /// Key of a change in the mod log, grouped by collection so that a collection's
+    /// changes since a modseq are a single range.
+    fn mod_key(modseq: u64, col: u32) -> Vec<u8> {
+        let base_mod_key = "colmod#";
+        let mut key: Vec<u8> = Vec::with_capacity(base_mod_key.len() + 4 + 8);
+        key.extend(base_mod_key.as_bytes());
 
-        let res = self.db
-            .get_cf(self.db.cf_handle("index").unwrap(), &key[..])?;
-        let elapsed = now.elapsed();
-        let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1000_000.0);
-        println!("find rocks: {} ms", sec);
+        let mut v: Vec<u8> = vec![0; 4];
+        BigEndian::write_u32(&mut v, col);
+        key.extend(&v[..]);
 
-        match res {
-            Some(r) => {
-                let docs: DocIdsMsg = DocIdsMsg::deserialize(r.deref());
+        let mut v: Vec<u8> = vec![0; 8];
+        BigEndian::write_u64(&mut v, modseq);
+        key.extend(&v[..]);
+        key
+    }
+
+    /// Adds `msgs` to `added_collections` and removes them from `removed_collections`,
+    /// logging one mod entry per affected collection. Only messages actually entering
+    /// or leaving a collection are changed. A collection cannot be in both lists.
+    pub fn modify(&self, msgs: &DocIdSet, added_collections: Vec<u32>, removed_collections: Vec<u32>) -> Result<(), StoreError> {
+        if let Some(col) = added_collections.iter().find(|c| removed_collections.contains(c)) {
+            return Err(StoreError::InvalidArgument(format!(
+                "collection {} both added and removed",
+                col
+            )));
+        }
+        if msgs.is_empty() {
+            return Ok(());
+        }
 
-                let elapsed = now.elapsed();
-                let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1000_000.0);
-                println!("fin: {} ms", sec);
-                Ok(Some(docs.0))
+        let cols = self.cols.read().unwrap();
+        let mut uids = self.uids.lock().unwrap();
+        Store::check_collections(&cols.0, added_collections.iter().chain(removed_collections.iter()))?;
+        for doc_id in msgs.iter() {
+            self.check_doc(doc_id)?;
+        }
+        let mut batch = WriteBatch::default();
+        for col in removed_collections {
+            let mut v: Vec<u8> = vec![0; 4];
+            BigEndian::write_u32(&mut v, col);
+            let key = Field::Cols.key(&v[..]);
+            let mut removed = msgs.clone();
+            removed.intersect_with(&self.get_docs(&key[..])?.unwrap_or_default());
+            if removed.is_empty() {
+                continue;
+            }
+            batch.merge_cf(
+                self.db.cf_handle("index").unwrap(),
+                &key[..],
+                &DocIdsMsg(DocIdSet::default(), removed.clone()).serialize()[..],
+            )?;
+            self.log_change(&mut batch, col, &Change::Remove(removed.clone()))?;
+            self.remove_uids(&mut batch, col, &removed)?;
+        }
+        for col in added_collections {
+            let mut v: Vec<u8> = vec![0; 4];
+            BigEndian::write_u32(&mut v, col);
+            let key = Field::Cols.key(&v[..]);
+            // messages already in the collection keep their UID
+            let mut added = msgs.clone();
+            added.difference_with(&self.get_docs(&key[..])?.unwrap_or_default());
+            if added.is_empty() {
+                continue;
             }
-            None => Ok(None),
+            batch.merge_cf(
+                self.db.cf_handle("index").unwrap(),
+                &key[..],
+                &DocIdsMsg(added.clone(), DocIdSet::default()).serialize()[..],
+            )?;
+            self.log_change(&mut batch, col, &Change::Add(added.clone()))?;
+            self.assign_uids(&mut batch, &mut uids, col, &added)?;
         }
+        self.commit(batch)?;
+        Ok(())
     }
 
-    pub fn find_by_col(&self, col_id: u32) -> Result<Option<DocIdSet>, StoreError> {
+    fn col_seq_key(col: u32) -> Vec<u8> {
         let mut key = Vec::new();
-        key.extend(b"msg#cols#".iter());
+        key.extend(b"col_seq#".iter());
         let mut v: Vec<u8> = vec![0; 4];
-        BigEndian::write_u32(&mut v, col_id);
+        BigEndian::write_u32(&mut v, col);
         key.extend(&v[..]);
-        use std::time::Instant;
-        let now = Instant::now();
+        key
+    }
 
-        let res = self.db
-            .get_cf(self.db.cf_handle("index").unwrap(), &key[..])?;
-        let elapsed = now.elapsed();
-        let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1000_000.0);
-        println!("find rocks: {} ms", sec);
+    /// `uid#<col><uid>` -> doc id, in the `col` column family.
+    fn uid_key(col: u32, uid: u32) -> Vec<u8> {
+        let mut key = Vec::new();
+        key.extend(b"uid#".iter());
+        key.extend(&DocId(col).write()[..]);
+        key.extend(&DocId(uid).write()[..]);
+        key
+    }
 
-        match res {
-            Some(r) => {
-                let docs: DocIdsMsg = DocIdsMsg::deserialize(r.deref());
+    /// `doc_uid#<col><doc_id>` -> uid, in the `col` column family.
+    fn doc_uid_key(col: u32, doc_id: u32) -> Vec<u8> {
+        let mut key = Vec::new();
+        key.extend(b"doc_uid#".iter());
+        key.extend(&DocId(col).write()[..]);
+        key.extend(&DocId(doc_id).write()[..]);
+        key
+    }
+
+    fn last_uid(&self, col: u32) -> Result<u32, StoreError> {
+        let value = self.db
+            .get_cf(self.db.cf_handle("col").unwrap(), &Store::col_seq_key(col)[..])?;
+        Ok(match value {
+            Some(value) => BigEndian::read_u32(&value),
+            None => 0,
+        })
+    }
+
+    /// Gives the next UIDs of `col` to `docs`, in doc id order. `uids` must stay locked
+    /// until `batch` is written so that UIDs are strictly increasing.
+    fn assign_uids(&self, batch: &mut WriteBatch, uids: &mut HashMap<u32, u32>, col: u32, docs: &DocIdSet) -> Result<(), StoreError> {
+        let mut last = match uids.get(&col) {
+            Some(last) => *last,
+            None => self.last_uid(col)?,
+        };
+        for doc_id in docs.iter() {
+            last += 1;
+            batch.put_cf(
+                self.db.cf_handle("col").unwrap(),
+                &Store::uid_key(col, last)[..],
+                &DocId(doc_id).write()[..],
+            )?;
+            batch.put_cf(
+                self.db.cf_handle("col").unwrap(),
+                &Store::doc_uid_key(col, doc_id)[..],
+                &DocId(last).write()[..],
+            )?;
+        }
+        batch.put_cf(
+            self.db.cf_handle("col").unwrap(),
+            &Store::col_seq_key(col)[..],
+            &DocId(last).write()[..],
+        )?;
+        uids.insert(col, last);
+        Ok(())
+    }
+
+    fn remove_uids(&self, batch: &mut WriteBatch, col: u32, docs: &DocIdSet) -> Result<(), StoreError> {
+        for doc_id in docs.iter() {
+            if let Some(uid) = self.uid(col, doc_id)? {
+                batch.delete_cf(self.db.cf_handle("col").unwrap(), &Store::uid_key(col, uid)[..])?;
+                batch.delete_cf(self.db.cf_handle("col").unwrap(), &Store::doc_uid_key(col, doc_id)[..])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// UID of a message in a collection.
+    pub fn uid(&self, col: u32, doc_id: u32) -> Result<Option<u32>, StoreError> {
+        let value = self.db
+            .get_cf(self.db.cf_handle("col").unwrap(), &Store::doc_uid_key(col, doc_id)[..])?;
+        Ok(value.map(|v| DocId::parse(v.deref()).0))
+    }
+
+    /// Doc id of the message holding `uid` in a collection.
+    pub fn doc_id_by_uid(&self, col: u32, uid: u32) -> Result<Option<u32>, StoreError> {
+        let value = self.db
+            .get_cf(self.db.cf_handle("col").unwrap(), &Store::uid_key(col, uid)[..])?;
+        Ok(value.map(|v| DocId::parse(v.deref()).0))
+    }
+
+    pub fn uid_validity(&self, col: u32) -> Result<u32, StoreError> {
+        Ok(self.collection_meta(col)?.uid_validity)
+    }
+
+    fn thread_lookup(&self, pending: &HashMap<Vec<u8>, u32>, key: &[u8]) -> Result<Option<u32>, StoreError> {
+        if let Some(thread_id) = pending.get(key) {
+            return Ok(Some(*thread_id));
+        }
+        let res = self.db.get_cf(self.db.cf_handle("thread").unwrap(), key)?;
+        Ok(res.map(|t| BigEndian::read_u32(t.deref())))
+    }
+
+    fn thread_record(
+        &self,
+        batch: &mut WriteBatch,
+        pending: &mut HashMap<Vec<u8>, u32>,
+        key: Vec<u8>,
+        thread_id: u32,
+    ) -> Result<(), StoreError> {
+        let mut v: Vec<u8> = vec![0; 4];
+        BigEndian::write_u32(&mut v, thread_id);
+        batch.put_cf(self.db.cf_handle("thread").unwrap(), &key[..], &v[..])?;
+        pending.insert(key, thread_id);
+        Ok(())
+    }
+
+    /// Finds the thread of a new message, or starts one identified by its doc id.
+    ///
+    /// The message joins the thread of the first of its own Message-ID, References and
+    /// In-Reply-To ids already known to the store; ids of messages not stored yet are
+    /// recorded too, so that a parent arriving after its replies joins their thread.
+    /// Replies whose ids are all unknown fall back to the latest thread started with
+    /// the same base subject. Threads are never merged: a message referencing two
+    /// existing threads joins the first one found.
+    fn resolve_thread(
+        &self,
+        batch: &mut WriteBatch,
+        pending: &mut HashMap<Vec<u8>, u32>,
+        doc_id: &DocId,
+        msg: &Msg,
+    ) -> Result<u32, StoreError> {
+        let mut ids: Vec<Vec<u8>> = vec![];
+        for id in msg.message_id.iter().chain(msg.references.iter()).chain(msg.in_reply_to.iter()) {
+            let key = format!("msgid#{}", id).into_bytes();
+            if !ids.contains(&key) {
+                ids.push(key);
+            }
+        }
+
+        let mut thread_id = None;
+        for key in ids.iter() {
+            thread_id = self.thread_lookup(pending, key)?;
+            if thread_id.is_some() {
+                break;
+            }
+        }
+
+        let (subject, reply) = match msg.subject {
+            Some(ref subject) => thread::base_subject(subject),
+            None => (String::new(), false),
+        };
+        let reply = reply || !msg.in_reply_to.is_empty() || !msg.references.is_empty();
+        let subject_key = format!("subject#{}", subject).into_bytes();
+        if thread_id.is_none() && reply && !subject.is_empty() {
+            thread_id = self.thread_lookup(pending, &subject_key)?;
+        }
+
+        let new_thread = thread_id.is_none();
+        let thread_id = thread_id.unwrap_or(doc_id.0);
+        for key in ids {
+            if self.thread_lookup(pending, &key)?.is_none() {
+                self.thread_record(batch, pending, key, thread_id)?;
+            }
+        }
+        if !subject.is_empty() && (new_thread || self.thread_lookup(pending, &subject_key)?.is_none()) {
+            self.thread_record(batch, pending, subject_key, thread_id)?;
+        }
+        Ok(thread_id)
+    }
+
+    /// Thread of a message, `None` for unknown messages and for the ones stored before
+    /// threading.
+    pub fn thread_of(&self, doc_id: u32) -> Result<Option<u32>, StoreError> {
+        let prefix = Field::Thread.prefix().as_bytes();
+        let keys = match self.doc_keys(doc_id)? {
+            Some(keys) => keys,
+            None => return Ok(None),
+        };
+        for key in keys.0 {
+            if key.starts_with(prefix) && key.len() == prefix.len() + 4 {
+                return Ok(Some(BigEndian::read_u32(&key[prefix.len()..])));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn find_thread(&self, thread_id: u32) -> Result<DocIdSet, StoreError> {
+        let mut v: Vec<u8> = vec![0; 4];
+        BigEndian::write_u32(&mut v, thread_id);
+        Ok(self.get_docs(&Field::Thread.key(&v[..])[..])?.unwrap_or_default())
+    }
 
-                let elapsed = now.elapsed();
-                let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1000_000.0);
-                println!("fin: {} ms", sec);
-                Ok(Some(docs.0))
+    /// Every message of the conversation `doc_id` belongs to, itself included.
+    pub fn conversation(&self, doc_id: u32) -> Result<DocIdSet, StoreError> {
+        match self.thread_of(doc_id)? {
+            Some(thread_id) => self.find_thread(thread_id),
+            None => {
+                let mut docs = DocIdSet::default();
+                docs.insert(doc_id);
+                Ok(docs)
             }
-            None => Ok(None),
         }
     }
+
+    /// Keeps the first message of each thread, in the order of `doc_ids`, typically
+    /// the page of a sorted `search`.
+    pub fn collapse_threads(&self, doc_ids: &[u32]) -> Result<Vec<u32>, StoreError> {
+        let mut seen = HashSet::new();
+        let mut ret = vec![];
+        for doc_id in doc_ids {
+            let first = match self.thread_of(*doc_id)? {
+                Some(thread_id) => seen.insert(thread_id),
+                None => true,
+            };
+            if first {
+                ret.push(*doc_id);
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Fails with `NotFound` for the first of `cols` that is not in `known`. Callers hold
+    /// the read lock of `known` until their write, so that `delete_collection` cannot
+    /// run in between.
+    fn check_collections<'a, I: Iterator<Item = &'a u32>>(known: &HashMap<u32, String>, cols: I) -> Result<(), StoreError> {
+        for col in cols {
+            if !known.contains_key(col) {
+                return Err(StoreError::NotFound(*col));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_doc(&self, doc_id: u32) -> Result<(), StoreError> {
+        match self.db
+            .get_cf(self.db.cf_handle("eml").unwrap(), &DocId(doc_id).write()[..])?
+        {
+            Some(_) => Ok(()),
+            None => Err(StoreError::NotFound(doc_id)),
+        }
+    }
+
+    /// Stores a message in `collections` and returns its doc id.
+    pub fn put(&self, collections: &Vec<u32>, msg: &Msg) -> Result<u32, StoreError> {
+        let doc_ids = self.write_msgs(vec![(collections, msg)])?;
+        Ok(doc_ids[0])
+    }
+
+    /// Stores many messages in a single write, with one merge operand per index key
+    /// for the whole batch. Returns the doc ids in the order of `msgs`.
+    pub fn put_batch(&self, msgs: Vec<(Vec<u32>, Msg)>) -> Result<Vec<u32>, StoreError> {
+        self.write_msgs(msgs.iter().map(|&(ref cols, ref msg)| (cols, msg)).collect())
+    }
+
+    fn write_msgs(&self, msgs: Vec<(&Vec<u32>, &Msg)>) -> Result<Vec<u32>, StoreError> {
+        let cols = self.cols.read().unwrap();
+        let mut uids = self.uids.lock().unwrap();
+        Store::check_collections(&cols.0, msgs.iter().flat_map(|&(c, _)| c.iter()))?;
+        let mut batch = WriteBatch::default();
+        let mut postings = Postings(HashMap::new());
+        let mut added: HashMap<u32, DocIdSet> = HashMap::new();
+        // thread records written by earlier messages of this batch
+        let mut threads: HashMap<Vec<u8>, u32> = HashMap::new();
+        let mut ret = Vec::with_capacity(msgs.len());
+
+        for (collections, msg) in msgs {
+            let doc_id = self.next_doc()?;
+            for col in collections {
+                added.entry(*col).or_insert_with(DocIdSet::default).insert(doc_id.0);
+            }
+            let thread_id = self.resolve_thread(&mut batch, &mut threads, &doc_id, msg)?;
+            self.shred_collections(&mut postings, &doc_id, collections);
+            self.shred(&mut batch, &mut postings, &doc_id, thread_id, msg)?;
+
+            {
+                let base_eml_key = "eml#";
+                let mut key: Vec<u8> = Vec::with_capacity(base_eml_key.len() + 4);
+                key.extend(&doc_id.write()[..]);
+                batch.put_cf(self.db.cf_handle("eml").unwrap(), &key[..], &msg.eml[..])?;
+            }
+            ret.push(doc_id.0);
+        }
+
+        // mod log
+        for (col, docs) in added {
+            self.assign_uids(&mut batch, &mut uids, col, &docs)?;
+            self.log_change(&mut batch, col, &Change::Add(docs))?;
+        }
+        postings.write(&self.db, &mut batch)?;
+        self.commit(batch)?;
+        Ok(ret)
+    }
+
+    /// Raw RFC 5322 bytes of a message, as given to `put`.
+    pub fn get_eml(&self, doc_id: u32) -> Result<Vec<u8>, StoreError> {
+        let res = self.db
+            .get_cf(self.db.cf_handle("eml").unwrap(), &DocId(doc_id).write()[..])?;
+        match res {
+            Some(eml) => Ok(eml.to_vec()),
+            None => Err(StoreError::NotFound(doc_id)),
+        }
+    }
+
+    /// Raw bytes of every message of `doc_ids`, in doc id order. Fails on the first
+    /// missing message.
+    pub fn multi_get_eml(&self, doc_ids: &DocIdSet) -> Result<Vec<(u32, Vec<u8>)>, StoreError> {
+        let mut ret = Vec::with_capacity(doc_ids.len() as usize);
+        for doc_id in doc_ids.iter() {
+            ret.push((doc_id, self.get_eml(doc_id)?));
+        }
+        Ok(ret)
+    }
+
+    pub fn compact(&self) {
+        self.db
+            .compact_range_cf(self.db.cf_handle("index").unwrap(), None, None);
+    }
+
+    /// Walks the whole date index, oldest first.
+    pub fn iterate_date(&self) -> Result<StoreIt, StoreError> {
+        self.iterate_date_from(i64::min_value(), Order::Asc)
+    }
+
+    /// Walks the date index starting at `date`, towards newer dates for `Order::Asc`
+    /// and towards older ones for `Order::Desc`. The start date is included.
+    pub fn iterate_date_from(&self, date: i64, order: Order) -> Result<StoreIt, StoreError> {
+        use rocksdb::{DBIterator, Direction, IteratorMode};
+        let segment = |from: i64| -> Result<(DBIterator, bool), StoreError> {
+            let mut v: Vec<u8> = vec![0; 8];
+            BigEndian::write_i64(&mut v, from);
+            let key = Field::Date.key(&v[..]);
+            let direction = match order {
+                Order::Asc => Direction::Forward,
+                Order::Desc => Direction::Reverse,
+            };
+            let it: DBIterator = self.db.iterator_cf(
+                self.db.cf_handle("index").unwrap(),
+                IteratorMode::From(&key[..], direction),
+            )?;
+            Ok((it, from < 0))
+        };
+        let mut segments = vec![segment(date)?];
+        match order {
+            Order::Asc if date < 0 => segments.push(segment(0)?),
+            Order::Desc if date >= 0 => segments.push(segment(-1)?),
+            _ => {}
+        }
+        Ok(StoreIt(segments))
+    }
+
+    /// Documents dated in `[from, to)`.
+    pub fn find_date_range(&self, from: i64, to: i64) -> Result<DocIdSet, StoreError> {
+        let mut ret = DocIdSet::default();
+        for (date, docs) in self.iterate_date_from(from, Order::Asc)? {
+            if date >= to {
+                break;
+            }
+            ret.union_with(&docs);
+        }
+        Ok(ret)
+    }
+
+    pub fn create_collection(&self, name: String) -> Result<Collection, StoreError> {
+        self.create_collection_with_role(name, None)
+    }
+
+    pub fn create_collection_with_role(&self, name: String, role: Option<Role>) -> Result<Collection, StoreError> {
+        let mut cols = self.cols.write().unwrap();
+        if cols.1.contains_key(&name) {
+            return Err(StoreError::DuplicateName(name));
+        }
+
+        let _uids = self.uids.lock().unwrap();
+        let doc_id = self.next_doc()?;
+        let mut key = Vec::new();
+        key.extend(b"collections#".iter());
+
+        let mut batch = WriteBatch::default();
+
+        let v = doc_id.write();
+        key.extend(&v[..]);
+
+        batch.put_cf(
+            self.db.cf_handle("col").unwrap(),
+            &key[..],
+            &name.as_bytes(),
+        )?;
+
+        use std::time::{SystemTime, UNIX_EPOCH};
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        let meta = CollectionMeta {
+            created: created,
+            uid_validity: self.next_uid_validity(&mut batch, created)?,
+            role: role,
+        };
+        batch.put_cf(
+            self.db.cf_handle("col").unwrap(),
+            &CollectionMeta::key(doc_id.0)[..],
+            &meta.serialize()[..],
+        )?;
+        self.db.write(batch)?;
+
+        cols.0.insert(doc_id.0, name.clone());
+        cols.1.insert(name.clone(), doc_id.0);
+        Ok(Collection(doc_id.0, name))
+    }
+
+    pub fn rename_collection(&self, col: u32, name: String) -> Result<Collection, StoreError> {
+        let mut cols = self.cols.write().unwrap();
+        let old = match cols.0.get(&col) {
+            Some(old) => old.clone(),
+            None => return Err(StoreError::NotFound(col)),
+        };
+        if old == name {
+            return Ok(Collection(col, name));
+        }
+
+        // sub collections follow their parent
+        let mut renames = vec![(col, old.clone(), name.clone())];
+        for child in Store::descendants(&cols.0, &old, self.delimiter) {
+            let new_name = format!("{}{}", name, &child.1[old.len()..]);
+            renames.push((child.0, child.1, new_name));
+        }
+        for &(_, _, ref new_name) in renames.iter() {
+            if cols.1.contains_key(new_name) {
+                return Err(StoreError::DuplicateName(new_name.clone()));
+            }
+        }
+
+        let mut batch = WriteBatch::default();
+        for &(id, _, ref new_name) in renames.iter() {
+            let mut key = Vec::new();
+            key.extend(b"collections#".iter());
+            key.extend(&DocId(id).write()[..]);
+            batch.put_cf(self.db.cf_handle("col").unwrap(), &key[..], &new_name.as_bytes())?;
+        }
+        self.db.write(batch)?;
+
+        for (id, old_name, new_name) in renames {
+            cols.1.remove(&old_name);
+            cols.0.insert(id, new_name.clone());
+            cols.1.insert(new_name, id);
+        }
+        Ok(Collection(col, name))
+    }
+
+    fn descendants(cols: &HashMap<u32, String>, name: &str, delimiter: char) -> Vec<Collection> {
+        let prefix = format!("{}{}", name, delimiter);
+        let mut ret: Vec<Collection> = cols.iter()
+            .filter(|&(_, n)| n.starts_with(&prefix))
+            .map(|(id, n)| Collection(*id, n.clone()))
+            .collect();
+        ret.sort_by(|a, b| a.1.cmp(&b.1));
+        ret
+    }
+
+    /// Closest existing collection above `name`: its parent level, or the level above
+    /// when the parent was never created, and so on.
+    fn nearest_ancestor(names: &HashMap<String, u32>, name: &str, delimiter: char) -> Option<Collection> {
+        let mut name = name;
+        while let Some(i) = name.rfind(delimiter) {
+            name = &name[..i];
+            if let Some(id) = names.get(name) {
+                return Some(Collection(*id, name.to_string()));
+            }
+        }
+        None
+    }
+
+    fn collection_name(&self, col: u32) -> Result<String, StoreError> {
+        match self.cols.read().unwrap().0.get(&col) {
+            Some(name) => Ok(name.clone()),
+            None => Err(StoreError::NotFound(col)),
+        }
+    }
+
+    /// Closest existing collection above `col`. `A/B/C` is a child of `A` when `A/B`
+    /// does not exist.
+    pub fn parent_collection(&self, col: u32) -> Result<Option<Collection>, StoreError> {
+        let name = self.collection_name(col)?;
+        let cols = self.cols.read().unwrap();
+        Ok(Store::nearest_ancestor(&cols.1, &name, self.delimiter))
+    }
+
+    /// Collections whose parent is `col`.
+    pub fn child_collections(&self, col: u32) -> Result<Vec<Collection>, StoreError> {
+        let name = self.collection_name(col)?;
+        let cols = self.cols.read().unwrap();
+        Ok(Store::descendants(&cols.0, &name, self.delimiter)
+            .into_iter()
+            .filter(|c| Store::nearest_ancestor(&cols.1, &c.1, self.delimiter).map(|p| p.0) == Some(col))
+            .collect())
+    }
+
+    /// Collections without a parent, including the ones below levels never created.
+    pub fn root_collections(&self) -> Vec<Collection> {
+        let cols = self.cols.read().unwrap();
+        let mut ret: Vec<Collection> = cols.0
+            .iter()
+            .filter(|&(_, n)| Store::nearest_ancestor(&cols.1, n, self.delimiter).is_none())
+            .map(|(id, n)| Collection(*id, n.clone()))
+            .collect();
+        ret.sort_by(|a, b| a.1.cmp(&b.1));
+        ret
+    }
+
+    /// `col` followed by every collection below it, at any depth.
+    pub fn subtree(&self, col: u32) -> Result<Vec<Collection>, StoreError> {
+        let name = self.collection_name(col)?;
+        let cols = self.cols.read().unwrap();
+        let mut ret = vec![Collection(col, name.clone())];
+        ret.extend(Store::descendants(&cols.0, &name, self.delimiter));
+        Ok(ret)
+    }
+
+    /// Messages of `col` and of every collection below it.
+    pub fn find_in_subtree(&self, col: u32) -> Result<DocIdSet, StoreError> {
+        let mut ret = DocIdSet::default();
+        for c in self.subtree(col)? {
+            let mut v: Vec<u8> = vec![0; 4];
+            BigEndian::write_u32(&mut v, c.0);
+            if let Some(docs) = self.get_docs(&Field::Cols.key(&v[..])[..])? {
+                ret.union_with(&docs);
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Deletes a collection, either untagging or expunging its messages, in a single write.
+    /// Collections with children must be emptied of them first.
+    pub fn delete_collection(&self, col: u32, mode: DeleteMode) -> Result<(), StoreError> {
+        let mut cols = self.cols.write().unwrap();
+        let name = match cols.0.get(&col) {
+            Some(name) => name.clone(),
+            None => return Err(StoreError::NotFound(col)),
+        };
+        if !Store::descendants(&cols.0, &name, self.delimiter).is_empty() {
+            return Err(StoreError::InvalidArgument(format!("collection {} has children", name)));
+        }
+
+        let mut v: Vec<u8> = vec![0; 4];
+        BigEndian::write_u32(&mut v, col);
+        let col_key = Field::Cols.key(&v[..]);
+        let docs = self.get_docs(&col_key[..])?.unwrap_or_default();
+
+        let mut uids = self.uids.lock().unwrap();
+        let mut batch = WriteBatch::default();
+        match mode {
+            DeleteMode::Untag => {
+                if !docs.is_empty() {
+                    self.log_change(&mut batch, col, &Change::Remove(docs))?;
+                }
+            }
+            DeleteMode::Expunge => self.delete_into(&mut batch, &docs)?,
+        }
+
+        let mut key = Vec::new();
+        key.extend(b"collections#".iter());
+        key.extend(&DocId(col).write()[..]);
+        batch.delete_cf(self.db.cf_handle("col").unwrap(), &key[..])?;
+        batch.delete_cf(self.db.cf_handle("col").unwrap(), &CollectionMeta::key(col)[..])?;
+        batch.delete_cf(self.db.cf_handle("col").unwrap(), &Store::col_seq_key(col)[..])?;
+        for prefix in [&b"uid#"[..], &b"doc_uid#"[..]].iter() {
+            let mut prefix = prefix.to_vec();
+            prefix.extend(&DocId(col).write()[..]);
+            for key in self.keys_with_prefix("col", &prefix[..])? {
+                batch.delete_cf(self.db.cf_handle("col").unwrap(), &key[..])?;
+            }
+        }
+        batch.delete_cf(self.db.cf_handle("index").unwrap(), &col_key[..])?;
+        let mod_prefix = Store::mod_key(0, col);
+        for key in self.keys_with_prefix("mod", &mod_prefix[..mod_prefix.len() - 8])? {
+            batch.delete_cf(self.db.cf_handle("mod").unwrap(), &key[..])?;
+        }
+        self.commit(batch)?;
+        uids.remove(&col);
+
+        cols.0.remove(&col);
+        cols.1.remove(&name);
+        Ok(())
+    }
+
+    /// UIDVALIDITY of a new collection: the creation time, unless a collection was
+    /// given that value or a later one already, so that a collection recreated under
+    /// the same name never reuses a UIDVALIDITY. Must be called with the `uids` lock
+    /// held until `batch` is written.
+    fn next_uid_validity(&self, batch: &mut WriteBatch, created: i64) -> Result<u32, StoreError> {
+        let key = b"uid_validity_max";
+        let last = self.db
+            .get_cf(self.db.cf_handle("col").unwrap(), key)?
+            .map(|v| BigEndian::read_u32(v.deref()))
+            .unwrap_or(0);
+        let uid_validity = (created as u32).max(last + 1);
+        let mut v: Vec<u8> = vec![0; 4];
+        BigEndian::write_u32(&mut v, uid_validity);
+        batch.put_cf(self.db.cf_handle("col").unwrap(), key, &v[..])?;
+        Ok(uid_validity)
+    }
+
+    /// Metadata of a collection. Collections created before metadata was recorded get
+    /// theirs on first access, with an unknown (0) creation time.
+    pub fn collection_meta(&self, col: u32) -> Result<CollectionMeta, StoreError> {
+        let get = || -> Result<Option<CollectionMeta>, StoreError> {
+            let res = self.db
+                .get_cf(self.db.cf_handle("col").unwrap(), &CollectionMeta::key(col)[..])?;
+            Ok(res.map(|meta| CollectionMeta::deserialize(meta.deref())))
+        };
+        if let Some(meta) = get()? {
+            return Ok(meta);
+        }
+        if !self.cols.read().unwrap().0.contains_key(&col) {
+            return Err(StoreError::NotFound(col));
+        }
+
+        let _uids = self.uids.lock().unwrap();
+        // created by a concurrent call while waiting for the lock
+        if let Some(meta) = get()? {
+            return Ok(meta);
+        }
+        use std::time::{SystemTime, UNIX_EPOCH};
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        let mut batch = WriteBatch::default();
+        let meta = CollectionMeta {
+            created: 0,
+            uid_validity: self.next_uid_validity(&mut batch, now)?,
+            role: None,
+        };
+        batch.put_cf(
+            self.db.cf_handle("col").unwrap(),
+            &CollectionMeta::key(col)[..],
+            &meta.serialize()[..],
+        )?;
+        self.db.write(batch)?;
+        Ok(meta)
+    }
+
+    pub fn set_collection_role(&self, col: u32, role: Option<Role>) -> Result<(), StoreError> {
+        let mut meta = self.collection_meta(col)?;
+        meta.role = role;
+        self.db.put_cf(
+            self.db.cf_handle("col").unwrap(),
+            &CollectionMeta::key(col)[..],
+            &meta.serialize()[..],
+        )?;
+        Ok(())
+    }
+
+    fn collections_internal(db: &DB) -> Result<Vec<Collection>, StoreError> {
+        let mut key = Vec::new();
+        key.extend(b"collections#".iter());
+
+        let mut ret = vec![];
+        use rocksdb::DBIterator;
+        let it: DBIterator = db.prefix_iterator_cf(db.cf_handle("col").unwrap(), &key[..])?;
+        for v in it {
+            let k = v.0;
+            if k.len() < b"collections#".len() || &k[0..b"collections#".len()] != b"collections#" {
+                break;
+            }
+
+            ret.push(Collection(
+                DocId::parse(&k[b"collections#".len()..]).0,
+                str::from_utf8(&v.1).unwrap().to_string(),
+            ))
+        }
+        Ok(ret)
+    }
+
+    pub fn collections(&self) -> Result<Vec<Collection>, StoreError> {
+        Store::collections_internal(&self.db)
+    }
+
+    pub fn find_by_name(&self, name: &str) -> Result<Option<DocIdSet>, StoreError> {
+        self.find(Field::Body, name)
+    }
+
+    /// Runs a query term through the analyzer of `field`, if it has one.
+    fn normalize(&self, field: Field, term: &str) -> Option<String> {
+        match self.analyzers.get(&field) {
+            Some(analyzer) => analyzer.normalize(term),
+            None => Some(term.to_string()),
+        }
+    }
+
+    /// Runs a prefix, wildcard or fuzzy pattern through the character-level filters of
+    /// the analyzer of `field`, if it has one.
+    fn normalize_pattern(&self, field: Field, pattern: &str) -> Option<String> {
+        match self.analyzers.get(&field) {
+            Some(analyzer) => analyzer.normalize_pattern(pattern),
+            None => Some(pattern.to_string()),
+        }
+    }
+
+    fn analyze(&self, field: Field, text: &str) -> Vec<Token> {
+        match self.analyzers.get(&field) {
+            Some(analyzer) => analyzer.analyze(text),
+            None => vec![
+                Token {
+                    position: 0,
+                    text: text.to_string(),
+                },
+            ],
+        }
+    }
+
+    /// Looks up a single term of a text field (`Subject`, `Body` or the display names
+    /// of an address field).
+    ///
+    /// A term the analyzer splits into several tokens, like a CJK word indexed as
+    /// n-grams, is matched as a phrase of those tokens. On address fields, a term with
+    /// an `@` is matched exactly against the addresses instead: `alice@example.com`
+    /// for one address, `@example.org` for a whole domain.
+    pub fn find(&self, field: Field, term: &str) -> Result<Option<DocIdSet>, StoreError> {
+        if field.address() && term.contains('@') {
+            return self.get_docs(&field.key(term.trim().to_lowercase().as_bytes())[..]);
+        }
+        let tokens = self.analyze(field, term);
+        match tokens.len() {
+            0 => Ok(None),
+            1 => self.get_docs(&field.key(tokens[0].text.as_bytes())[..]),
+            _ => Ok(Some(self.match_phrase(field, &tokens[..])?)),
+        }
+    }
+
+    /// Messages whose header `name` matches `value`: the whole value for keyword
+    /// headers, every term of it for text headers. Empty for headers not configured
+    /// with `StoreConfig::header`.
+    pub fn find_header(&self, name: &str, value: &str) -> Result<DocIdSet, StoreError> {
+        let name = name.trim().to_lowercase();
+        let terms = match self.headers.get(&name) {
+            Some(&HeaderIndex::Keyword) => vec![value.trim().to_lowercase()],
+            Some(&HeaderIndex::Text) => self.header_analyzer.analyze(value).into_iter().map(|t| t.text).collect(),
+            None => vec![],
+        };
+        let mut ret: Option<DocIdSet> = None;
+        for term in terms {
+            let docs = self.get_docs(&Store::header_key(&name, &term)[..])?.unwrap_or_default();
+            ret = Some(match ret {
+                Some(mut acc) => {
+                    acc.intersect_with(&docs);
+                    acc
+                }
+                None => docs,
+            });
+        }
+        Ok(ret.unwrap_or_default())
+    }
+
+    /// Messages with at least one attachment.
+    pub fn find_with_attachments(&self) -> Result<DocIdSet, StoreError> {
+        Ok(self.get_docs(&Field::Has.key(b"attachment")[..])?.unwrap_or_default())
+    }
+
+    /// Messages with an attachment of a size in `[min, max)`.
+    pub fn find_attachment_size(&self, min: u64, max: u64) -> Result<DocIdSet, StoreError> {
+        let mut v: Vec<u8> = vec![0; 8];
+        BigEndian::write_u64(&mut v, min);
+        let start = Field::Size.key(&v[..]);
+        let prefix = Field::Size.prefix().as_bytes();
+
+        use rocksdb::{DBIterator, Direction, IteratorMode};
+        let it: DBIterator = self.db.iterator_cf(
+            self.db.cf_handle("index").unwrap(),
+            IteratorMode::From(&start[..], Direction::Forward),
+        )?;
+        let mut ret = DocIdSet::default();
+        for (key, value) in it {
+            if !key.starts_with(prefix) || key.len() != prefix.len() + 8 {
+                break;
+            }
+            if BigEndian::read_u64(&key[prefix.len()..]) >= max {
+                break;
+            }
+            ret.union_with(&DocIdsMsg::deserialize(&value).0);
+        }
+        Ok(ret)
+    }
+
+    pub fn find_by_date(&self, date: i64) -> Result<Option<DocIdSet>, StoreError> {
+        let mut v: Vec<u8> = vec![0; 8];
+        BigEndian::write_i64(&mut v, date);
+        self.get_docs(&Field::Date.key(&v[..])[..])
+    }
+
+    pub fn find_by_col(&self, col_id: u32) -> Result<Option<DocIdSet>, StoreError> {
+        let mut v: Vec<u8> = vec![0; 4];
+        BigEndian::write_u32(&mut v, col_id);
+        self.get_docs(&Field::Cols.key(&v[..])[..])
+    }
+
+    fn get_docs(&self, key: &[u8]) -> Result<Option<DocIdSet>, StoreError> {
+        let res = self.db
+            .get_cf(self.db.cf_handle("index").unwrap(), key)?;
+        Ok(res.map(|r| DocIdsMsg::deserialize(r.deref()).0))
+    }
+
+    fn positions(&self, field: Field, term: &str, doc_id: u32) -> Result<Positions, StoreError> {
+        let res = self.db.get_cf(
+            self.db.cf_handle("pos").unwrap(),
+            &Positions::key(field, term, &DocId(doc_id))[..],
+        )?;
+        Ok(match res {
+            Some(r) => Positions::deserialize(r.deref()),
+            None => Positions(vec![]),
+        })
+    }
+
+    fn find_all(&self, field: Field, terms: &[&str]) -> Result<DocIdSet, StoreError> {
+        let mut ret: Option<DocIdSet> = None;
+        for term in terms {
+            let docs = self.get_docs(&field.key(term.as_bytes())[..])?.unwrap_or_default();
+            ret = Some(match ret {
+                Some(mut r) => {
+                    r.intersect_with(&docs);
+                    r
+                }
+                None => docs,
+            });
+        }
+        Ok(ret.unwrap_or_default())
+    }
+
+    fn expand_terms<F>(&self, field: Field, prefix: &str, max_terms: usize, matches: F) -> Result<Vec<(String, DocIdSet)>, StoreError>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let field_prefix = field.prefix().as_bytes();
+        let key = field.key(prefix.as_bytes());
+
+        let mut ret = vec![];
+        use rocksdb::DBIterator;
+        let it: DBIterator = self.db
+            .prefix_iterator_cf(self.db.cf_handle("index").unwrap(), &key[..])?;
+        for (k, v) in it {
+            if k.len() < key.len() || &k[..key.len()] != &key[..] {
+                break;
+            }
+            let term = match str::from_utf8(&k[field_prefix.len()..]) {
+                Ok(term) => term,
+                Err(_) => continue,
+            };
+            if !matches(term) {
+                continue;
+            }
+            if ret.len() == max_terms {
+                return Err(StoreError::TooManyTerms(max_terms));
+            }
+            ret.push((term.to_string(), DocIdsMsg::deserialize(&v).0));
+        }
+        Ok(ret)
+    }
+
+    fn union_terms(terms: Vec<(String, DocIdSet)>) -> DocIdSet {
+        let mut ret = DocIdSet::default();
+        for (_, docs) in terms {
+            ret.union_with(&docs);
+        }
+        ret
+    }
+
+    /// Union of the documents of every term of `field` starting with `prefix`.
+    pub fn find_prefix(&self, field: Field, prefix: &str, max_terms: usize) -> Result<DocIdSet, StoreError> {
+        let prefix = match self.normalize_pattern(field, prefix) {
+            Some(prefix) => prefix,
+            None => return Ok(DocIdSet::default()),
+        };
+        let terms = self.expand_terms(field, &prefix, max_terms, |_| true)?;
+        Ok(Store::union_terms(terms))
+    }
+
+    /// Union of the documents of every term of `field` matching a `*`/`?` pattern.
+    pub fn find_wildcard(&self, field: Field, pattern: &str, max_terms: usize) -> Result<DocIdSet, StoreError> {
+        let pattern = match self.normalize_pattern(field, pattern) {
+            Some(pattern) => pattern,
+            None => return Ok(DocIdSet::default()),
+        };
+        let prefix = matcher::wildcard_prefix(&pattern);
+        let terms = self.expand_terms(field, prefix, max_terms, |term| matcher::wildcard_match(&pattern, term))?;
+        Ok(Store::union_terms(terms))
+    }
+
+    /// Union of the documents of every term of `field` within `max_edits` (capped at 2)
+    /// Levenshtein edits of `term`.
+    pub fn find_fuzzy(&self, field: Field, term: &str, max_edits: u32) -> Result<FuzzyMatch, StoreError> {
+        let term = match self.normalize_pattern(field, term) {
+            Some(term) => term,
+            None => {
+                return Ok(FuzzyMatch {
+                    docs: DocIdSet::default(),
+                    terms: vec![],
+                })
+            }
+        };
+        let automaton = matcher::Levenshtein::new(&term, max_edits.min(2));
+        let field_prefix = field.prefix().as_bytes();
+
+        use rocksdb::{DBIterator, Direction, IteratorMode};
+        let mut it: DBIterator = self.db.iterator_cf(
+            self.db.cf_handle("index").unwrap(),
+            IteratorMode::From(field_prefix, Direction::Forward),
+        )?;
+
+        let mut ret = FuzzyMatch {
+            docs: DocIdSet::default(),
+            terms: vec![],
+        };
+        // states[i] is the automaton state after the first i chars of `previous`
+        let mut states = vec![automaton.start()];
+        let mut previous: Vec<char> = vec![];
+        while let Some((k, v)) = it.next() {
+            if k.len() < field_prefix.len() || &k[..field_prefix.len()] != field_prefix {
+                break;
+            }
+            let candidate: Vec<char> = match str::from_utf8(&k[field_prefix.len()..]) {
+                Ok(candidate) => candidate.chars().collect(),
+                Err(_) => continue,
+            };
+            let common = previous
+                .iter()
+                .zip(candidate.iter())
+                .take_while(|&(a, b)| a == b)
+                .count();
+            states.truncate(common + 1);
+
+            let mut dead = None;
+            for i in common..candidate.len() {
+                let state = automaton.step(&states[i], candidate[i]);
+                if !automaton.can_match(&state) {
+                    dead = Some(i);
+                    break;
+                }
+                states.push(state);
+            }
+
+            match dead {
+                Some(i) => {
+                    // nothing starting with candidate[..i + 1] can match, seek past it
+                    let dead_prefix: String = candidate[..i + 1].iter().collect();
+                    let mut skip = field.key(dead_prefix.as_bytes());
+                    while let Some(last) = skip.pop() {
+                        if last < 0xFF {
+                            skip.push(last + 1);
+                            break;
+                        }
+                    }
+                    previous = candidate[..i].to_vec();
+                    it.set_mode(IteratorMode::From(&skip[..], Direction::Forward));
+                }
+                None => {
+                    if automaton.is_match(&states[candidate.len()]) {
+                        if ret.terms.len() == MAX_EXPANSIONS {
+                            return Err(StoreError::TooManyTerms(MAX_EXPANSIONS));
+                        }
+                        ret.docs.union_with(&DocIdsMsg::deserialize(&v).0);
+                        ret.terms.push(candidate.iter().collect());
+                    }
+                    previous = candidate;
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Documents where `terms` appear consecutively, in order, in a positional field.
+    pub fn find_phrase(&self, field: Field, terms: &[&str]) -> Result<DocIdSet, StoreError> {
+        let tokens = self.analyze(field, &terms.join(" "));
+        self.match_phrase(field, &tokens[..])
+    }
+
+    fn match_phrase(&self, field: Field, tokens: &[Token]) -> Result<DocIdSet, StoreError> {
+        let terms: Vec<&str> = tokens.iter().map(|t| t.text.as_str()).collect();
+        let candidates = self.find_all(field, &terms[..])?;
+        if tokens.len() < 2 || !field.positional() {
+            return Ok(candidates);
+        }
+
+        // positions are relative to the first token, so that words dropped by the
+        // analyzer still leave a gap in the phrase
+        let first = tokens[0].position;
+        let mut ret = DocIdSet::default();
+        for doc_id in candidates.iter() {
+            let mut positions = Vec::with_capacity(tokens.len());
+            for term in terms.iter() {
+                positions.push(self.positions(field, term, doc_id)?);
+            }
+            let found = positions[0].0.iter().any(|start| {
+                positions[1..]
+                    .iter()
+                    .zip(tokens[1..].iter())
+                    .all(|(p, t)| p.contains(start + t.position - first))
+            });
+            if found {
+                ret.insert(doc_id);
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Documents where `a` and `b` appear, in any order, at most `distance` words apart.
+    pub fn find_near(&self, field: Field, a: &str, b: &str, distance: u32) -> Result<DocIdSet, StoreError> {
+        let (a, b) = match (self.normalize(field, a), self.normalize(field, b)) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return Ok(DocIdSet::default()),
+        };
+        let candidates = self.find_all(field, &[&a[..], &b[..]])?;
+        if !field.positional() {
+            return Ok(candidates);
+        }
+
+        let mut ret = DocIdSet::default();
+        for doc_id in candidates.iter() {
+            let pa = self.positions(field, &a, doc_id)?;
+            let pb = self.positions(field, &b, doc_id)?;
+            if pa.within(&pb, distance) {
+                ret.insert(doc_id);
+            }
+        }
+        Ok(ret)
+    }
+
+    fn all_docs(&self) -> Result<DocIdSet, StoreError> {
+        // every message is shredded under exactly one msg#date# key
+        let mut ret = DocIdSet::default();
+        for (_, docs) in self.iterate_date()? {
+            ret.union_with(&docs);
+        }
+        Ok(ret)
+    }
+
+    /// Counts, for every collection, how many of `docs` it holds, in a single pass
+    /// over the collection bitmaps.
+    pub fn facets(&self, docs: &DocIdSet) -> Result<Vec<Facet>, StoreError> {
+        let seen = self.find_by_flag(&Flag::Seen)?.unwrap_or_default();
+        let prefix = Field::Cols.prefix().as_bytes();
+
+        let mut ret = vec![];
+        use rocksdb::DBIterator;
+        let it: DBIterator = self.db
+            .prefix_iterator_cf(self.db.cf_handle("index").unwrap(), prefix)?;
+        for (k, v) in it {
+            if k.len() != prefix.len() + 4 || &k[..prefix.len()] != prefix {
+                break;
+            }
+            let mut matched = DocIdsMsg::deserialize(&v).0;
+            matched.intersect_with(docs);
+            let count = matched.len();
+            matched.difference_with(&seen);
+            ret.push(Facet {
+                col: BigEndian::read_u32(&k[prefix.len()..]),
+                count: count,
+                unread: matched.len(),
+            });
+        }
+        Ok(ret)
+    }
+
+    /// Evaluates `query` and returns up to `limit` matches in `sort` order, starting
+    /// after `cursor`. Messages with the same date come in doc id order, reversed for
+    /// `Order::Desc`.
+    ///
+    /// The date index is walked from the cursor, so a page only costs the dates it
+    /// spans and never sorts the whole result.
+    pub fn search(&self, query: &Query, sort: Sort, limit: usize, cursor: Option<&Cursor>) -> Result<Page, StoreError> {
+        let after = match cursor {
+            Some(c) => match c.position() {
+                Some(position) => Some(position),
+                None => return Err(StoreError::InvalidArgument("malformed cursor".to_string())),
+            },
+            None => None,
+        };
+        let matches = self.query(query)?;
+        let mut ret = Page {
+            doc_ids: vec![],
+            next: None,
+        };
+        if limit == 0 || matches.is_empty() {
+            return Ok(ret);
+        }
+
+        let Sort::Date(order) = sort;
+        let it = match (after, order) {
+            (Some((date, _)), _) => self.iterate_date_from(date, order)?,
+            (None, Order::Asc) => self.iterate_date()?,
+            (None, Order::Desc) => self.iterate_date_from(i64::max_value(), order)?,
+        };
+
+        let mut last_date = 0;
+        for (date, mut docs) in it {
+            docs.intersect_with(&matches);
+            let mut docs: Vec<u32> = docs.iter().collect();
+            if order == Order::Desc {
+                docs.reverse();
+            }
+            for doc_id in docs {
+                if let Some((after_date, after_doc)) = after {
+                    let seen = match order {
+                        Order::Asc => doc_id <= after_doc,
+                        Order::Desc => doc_id >= after_doc,
+                    };
+                    if date == after_date && seen {
+                        continue;
+                    }
+                }
+                if ret.doc_ids.len() == limit {
+                    ret.next = Some(Cursor::new(last_date, ret.doc_ids[limit - 1]));
+                    return Ok(ret);
+                }
+                ret.doc_ids.push(doc_id);
+                last_date = date;
+            }
+        }
+        Ok(ret)
+    }
+
+    pub fn query(&self, query: &Query) -> Result<DocIdSet, StoreError> {
+        match *query {
+            Query::Term(field, ref term) => Ok(self.find(field, term)?.unwrap_or_default()),
+            Query::Prefix(field, ref prefix) => self.find_prefix(field, prefix, MAX_EXPANSIONS),
+            Query::Wildcard(field, ref pattern) => self.find_wildcard(field, pattern, MAX_EXPANSIONS),
+            Query::Fuzzy(field, ref term, max_edits) => Ok(self.find_fuzzy(field, term, max_edits)?.docs),
+            Query::Phrase(field, ref terms) => {
+                let terms: Vec<&str> = terms.iter().map(|t| t.as_str()).collect();
+                self.find_phrase(field, &terms[..])
+            }
+            Query::Near(field, ref a, ref b, distance) => self.find_near(field, a, b, distance),
+            Query::Col(col_id) => {
+                let mut v: Vec<u8> = vec![0; 4];
+                BigEndian::write_u32(&mut v, col_id);
+                Ok(self.get_docs(&Field::Cols.key(&v[..])[..])?.unwrap_or_default())
+            }
+            Query::Subtree(col_id) => self.find_in_subtree(col_id),
+            Query::Date(date) => Ok(self.find_by_date(date)?.unwrap_or_default()),
+            Query::DateRange(from, to) => self.find_date_range(from, to),
+            Query::Flag(ref flag) => Ok(self.find_by_flag(flag)?.unwrap_or_default()),
+            Query::Thread(thread_id) => self.find_thread(thread_id),
+            Query::Header(ref name, ref value) => self.find_header(name, value),
+            Query::HasAttachment => self.find_with_attachments(),
+            Query::Larger(size) => self.find_attachment_size(size.saturating_add(1), u64::max_value()),
+            Query::Smaller(size) => self.find_attachment_size(0, size),
+            Query::And(ref queries) => {
+                // negated clauses are subtracted from the intersection instead of
+                // being evaluated against the whole store
+                let mut ret: Option<DocIdSet> = None;
+                let mut excluded = DocIdSet::default();
+                for q in queries {
+                    if let Query::Not(ref q) = *q {
+                        excluded.union_with(&self.query(q)?);
+                        continue;
+                    }
+                    let docs = self.query(q)?;
+                    ret = Some(match ret {
+                        Some(mut r) => {
+                            r.intersect_with(&docs);
+                            r
+                        }
+                        None => docs,
+                    });
+                    if ret.as_ref().map_or(false, |r| r.is_empty()) {
+                        return Ok(DocIdSet::default());
+                    }
+                }
+                let mut ret = match ret {
+                    Some(r) => r,
+                    None => self.all_docs()?,
+                };
+                ret.difference_with(&excluded);
+                Ok(ret)
+            }
+            Query::Or(ref queries) => {
+                let mut ret = DocIdSet::default();
+                for q in queries {
+                    ret.union_with(&self.query(q)?);
+                }
+                Ok(ret)
+            }
+            Query::Not(ref q) => {
+                let mut ret = self.all_docs()?;
+                ret.difference_with(&self.query(q)?);
+                Ok(ret)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analysis::Language;
+
+    fn docs(ids: &[u32]) -> DocIdSet {
+        ids.iter().cloned().collect()
+    }
+
+    /// Runs `f` on a store opened in a fresh directory, removed afterwards.
+    fn with_store<F: FnOnce(&Store)>(name: &str, f: F) {
+        let path = ::std::env::temp_dir().join(format!("rocky-test-{}-{}", name, ::std::process::id()));
+        let _ = ::std::fs::remove_dir_all(&path);
+        {
+            let store = Store::open(path.to_str().unwrap()).unwrap();
+            f(&store);
+        }
+        ::std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    fn msg(subject: &str, text: &str, date: i64) -> Msg {
+        Msg {
+            subject: Some(subject.to_string()),
+            from: vec![],
+            to: vec![],
+            cc: vec![],
+            bcc: vec![],
+            reply_to: vec![],
+            text: text.to_string(),
+            date: date,
+            eml: text.as_bytes().to_vec(),
+            headers: vec![],
+            mime: None,
+            message_id: None,
+            in_reply_to: vec![],
+            references: vec![],
+        }
+    }
+
+    #[test]
+    fn test_merge_docs_in_order() {
+        let base = DocIdsMsg(docs(&[1, 2]), docs(&[]));
+        let ops = vec![DocIdsMsg(docs(&[]), docs(&[2])), DocIdsMsg(docs(&[2, 3]), docs(&[]))];
+        let ret = merge_docs(Some(base), ops);
+        assert_eq!(ret.0, docs(&[1, 2, 3]));
+        assert!(ret.1.is_empty());
+    }
+
+    #[test]
+    fn test_partial_merge_keeps_removals() {
+        let ops = vec![DocIdsMsg(docs(&[]), docs(&[1, 2])), DocIdsMsg(docs(&[3]), docs(&[]))];
+        let partial = merge_docs(None, ops);
+        assert_eq!(partial.0, docs(&[3]));
+        assert_eq!(partial.1, docs(&[1, 2]));
+
+        let base = DocIdsMsg(docs(&[1, 2, 4]), docs(&[]));
+        let ret = merge_docs(Some(base), vec![partial]);
+        assert_eq!(ret.0, docs(&[3, 4]));
+    }
+
+    #[test]
+    fn test_partial_merge_add_then_remove() {
+        let ops = vec![DocIdsMsg(docs(&[1]), docs(&[])), DocIdsMsg(docs(&[]), docs(&[1]))];
+        let partial = merge_docs(None, ops);
+        assert!(partial.0.is_empty());
+        assert_eq!(partial.1, docs(&[1]));
+    }
+
+    #[test]
+    fn test_find_fuzzy() {
+        with_store("fuzzy", |store| {
+            let words = ["apple", "kit", "kitchen", "kitten", "kittens", "mitten", "sitting", "zebra"];
+            let msgs = words.iter().map(|w| (vec![], msg("", w, 0))).collect();
+            let ids = store.put_batch(msgs).unwrap();
+
+            let found = store.find_fuzzy(Field::Body, "Kitten", 1).unwrap();
+            assert_eq!(found.terms, vec!["kitten", "kittens", "mitten"]);
+            assert_eq!(found.docs, docs(&[ids[3], ids[4], ids[5]]));
+
+            let found = store.find_fuzzy(Field::Body, "kitten", 0).unwrap();
+            assert_eq!(found.terms, vec!["kitten"]);
+
+            // the walk skips past "ki" dead prefixes without losing later terms
+            let found = store.find_fuzzy(Field::Body, "zebu", 2).unwrap();
+            assert_eq!(found.terms, vec!["zebra"]);
+
+            let found = store.find_fuzzy(Field::Body, "", 0).unwrap();
+            assert!(found.terms.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_search_pages() {
+        with_store("search", |store| {
+            let col = store.create_collection("INBOX".to_string()).unwrap();
+            let dates = [20, -100, 10, 0, -5, 10];
+            let msgs = dates.iter().map(|d| (vec![col.0], msg("", "text", *d))).collect();
+            let ids = store.put_batch(msgs).unwrap();
+            let query = Query::Col(col.0);
+
+            let mut pages = vec![];
+            let mut cursor = None;
+            loop {
+                let page = store.search(&query, Sort::Date(Order::Asc), 2, cursor.as_ref()).unwrap();
+                pages.push(page.doc_ids);
+                cursor = page.next;
+                if cursor.is_none() {
+                    break;
+                }
+            }
+            assert_eq!(pages, vec![vec![ids[1], ids[4]], vec![ids[3], ids[2]], vec![ids[5], ids[0]]]);
+
+            let page = store.search(&query, Sort::Date(Order::Desc), 3, None).unwrap();
+            assert_eq!(page.doc_ids, vec![ids[0], ids[5], ids[2]]);
+            let cursor = Cursor::from_bytes(page.next.unwrap().as_bytes().to_vec());
+            let page = store.search(&query, Sort::Date(Order::Desc), 3, Some(&cursor)).unwrap();
+            assert_eq!(page.doc_ids, vec![ids[3], ids[4], ids[1]]);
+            assert_eq!(page.next, None);
+
+            let page = store.search(&query, Sort::Date(Order::Asc), 0, None).unwrap();
+            assert!(page.doc_ids.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_find_date_range_across_epoch() {
+        with_store("dates", |store| {
+            let dates = [-200, -100, -1, 0, 99, 100];
+            let msgs = dates.iter().map(|d| (vec![], msg("", "text", *d))).collect();
+            let ids = store.put_batch(msgs).unwrap();
+
+            assert_eq!(store.find_date_range(-100, 100).unwrap(), docs(&ids[1..5]));
+            assert_eq!(store.find_date_range(0, 101).unwrap(), docs(&ids[3..6]));
+            assert_eq!(store.find_date_range(-300, -1).unwrap(), docs(&ids[0..2]));
+            let all: Vec<i64> = store.iterate_date().unwrap().map(|(d, _)| d).collect();
+            assert_eq!(all, dates.to_vec());
+        });
+    }
+
+    #[test]
+    fn test_patterns_skip_stop_words_and_stemming() {
+        let path = ::std::env::temp_dir().join(format!("rocky-test-patterns-{}", ::std::process::id()));
+        let _ = ::std::fs::remove_dir_all(&path);
+        {
+            let config = StoreConfig::default().analyzer(Field::Body, Box::new(TextAnalyzer::language(Language::English)));
+            let store = Store::open_with_config(path.to_str().unwrap(), config).unwrap();
+            let ids = store
+                .put_batch(vec![(vec![], msg("", "theory apple", 0)), (vec![], msg("", "thermal invoices", 0))])
+                .unwrap();
+
+            // "the" and "a" are stop words, but valid prefixes
+            assert_eq!(store.find_prefix(Field::Body, "The", MAX_EXPANSIONS).unwrap(), docs(&ids));
+            assert_eq!(store.find_prefix(Field::Body, "a", MAX_EXPANSIONS).unwrap(), docs(&ids[..1]));
+            assert_eq!(store.find_wildcard(Field::Body, "the?m*", MAX_EXPANSIONS).unwrap(), docs(&ids[1..]));
+            // indexed stemmed as "invoic", the pattern is not stemmed
+            assert_eq!(store.find_prefix(Field::Body, "invoic", MAX_EXPANSIONS).unwrap(), docs(&ids[1..]));
+            assert_eq!(store.find_wildcard(Field::Body, "INVOIC*", MAX_EXPANSIONS).unwrap(), docs(&ids[1..]));
+        }
+        ::std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_collection_tree() {
+        with_store("tree", |store| {
+            let ab = store.create_collection("A/B".to_string()).unwrap();
+            let abc = store.create_collection("A/B/C".to_string()).unwrap();
+            let adc = store.create_collection("A/D/C".to_string()).unwrap();
+            assert_eq!(store.root_collections(), vec![ab.clone(), adc.clone()]);
+            assert_eq!(store.parent_collection(ab.0).unwrap(), None);
+
+            let a = store.create_collection("A".to_string()).unwrap();
+            assert_eq!(store.root_collections(), vec![a.clone()]);
+            assert_eq!(store.parent_collection(adc.0).unwrap(), Some(a.clone()));
+            assert_eq!(store.child_collections(a.0).unwrap(), vec![ab.clone(), adc.clone()]);
+            assert_eq!(store.child_collections(ab.0).unwrap(), vec![abc.clone()]);
+
+            match store.delete_collection(a.0, DeleteMode::Untag) {
+                Err(StoreError::InvalidArgument(_)) => {}
+                r => panic!("unexpected {:?}", r),
+            }
+            store.delete_collection(abc.0, DeleteMode::Untag).unwrap();
+            assert_eq!(store.child_collections(ab.0).unwrap(), vec![]);
+        });
+    }
+
+    #[test]
+    fn test_delimiter_is_recorded() {
+        let path = ::std::env::temp_dir().join(format!("rocky-test-delimiter-{}", ::std::process::id()));
+        let _ = ::std::fs::remove_dir_all(&path);
+        {
+            Store::open_with_config(path.to_str().unwrap(), StoreConfig::default().delimiter('.')).unwrap();
+        }
+        match Store::open(path.to_str().unwrap()) {
+            Err(StoreError::DelimiterMismatch(_)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        Store::open_with_config(path.to_str().unwrap(), StoreConfig::default().delimiter('.')).unwrap();
+        ::std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_collections_and_docs() {
+        with_store("unknown", |store| {
+            let col = store.create_collection("INBOX".to_string()).unwrap();
+            assert_eq!(store.put(&vec![col.0 + 100], &msg("", "text", 0)), Err(StoreError::NotFound(col.0 + 100)));
+            let id = store.put(&vec![col.0], &msg("", "text", 0)).unwrap();
+
+            assert_eq!(store.modify(&docs(&[id]), vec![col.0 + 100], vec![]), Err(StoreError::NotFound(col.0 + 100)));
+            assert_eq!(store.modify(&docs(&[id + 100]), vec![], vec![col.0]), Err(StoreError::NotFound(id + 100)));
+            assert_eq!(store.facets(&docs(&[id])).unwrap().len(), 1);
+            assert_eq!(store.changes_since(0, col.0).unwrap().changes.len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_search_malformed_cursor() {
+        with_store("cursor", |store| {
+            let cursor = Cursor::from_bytes(b"garbage".to_vec());
+            match store.search(&Query::Date(0), Sort::Date(Order::Asc), 10, Some(&cursor)) {
+                Err(StoreError::InvalidArgument(_)) => {}
+                r => panic!("unexpected {:?}", r),
+            }
+        });
+    }
+
+    #[test]
+    fn test_delete() {
+        with_store("delete", |store| {
+            let inbox = store.create_collection("INBOX".to_string()).unwrap();
+            let archive = store.create_collection("Archive".to_string()).unwrap();
+            let cols = vec![inbox.0, archive.0];
+            let ids = store
+                .put_batch(vec![(cols.clone(), msg("hello", "hello world", 0)), (cols.clone(), msg("hello", "hello", 0))])
+                .unwrap();
+            let (id, other) = (ids[0], ids[1]);
+            store.set_flags(&docs(&[id, other]), &[Flag::Seen]).unwrap();
+
+            // nothing is written when one of the messages does not exist
+            assert_eq!(store.delete(&docs(&[id, other + 100])), Err(StoreError::NotFound(other + 100)));
+            assert_eq!(store.find(Field::Body, "world").unwrap(), Some(docs(&[id])));
+            assert_eq!(store.find_by_col(inbox.0).unwrap(), Some(docs(&ids)));
+
+            store.delete(&docs(&[id])).unwrap();
+            assert_eq!(store.find(Field::Body, "world").unwrap().unwrap_or_default(), docs(&[]));
+            assert_eq!(store.find(Field::Body, "hello").unwrap(), Some(docs(&[other])));
+            assert_eq!(store.find(Field::Subject, "hello").unwrap(), Some(docs(&[other])));
+            assert_eq!(store.find_by_col(inbox.0).unwrap(), Some(docs(&[other])));
+            assert_eq!(store.find_by_col(archive.0).unwrap(), Some(docs(&[other])));
+            assert_eq!(store.find_by_flag(&Flag::Seen).unwrap(), Some(docs(&[other])));
+            assert!(store.positions(Field::Body, "hello", id).unwrap().0.is_empty());
+            assert!(store.positions(Field::Subject, "hello", id).unwrap().0.is_empty());
+            assert!(!store.positions(Field::Body, "hello", other).unwrap().0.is_empty());
+            assert_eq!(store.get_eml(id), Err(StoreError::NotFound(id)));
+            assert_eq!(store.uid(inbox.0, id).unwrap(), None);
+
+            for col in cols {
+                let changes: Vec<Change> = store.changes_since(0, col).unwrap().changes.into_iter().map(|c| c.1).collect();
+                assert_eq!(
+                    changes,
+                    vec![Change::Add(docs(&ids)), Change::Flags(docs(&ids)), Change::Remove(docs(&[id]))]
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn test_changes_since() {
+        with_store("changes", |store| {
+            let inbox = store.create_collection("INBOX".to_string()).unwrap();
+            let archive = store.create_collection("Archive".to_string()).unwrap();
+            let id = store.put(&vec![inbox.0], &msg("", "text", 0)).unwrap();
+            let other = store.put(&vec![archive.0], &msg("", "text", 0)).unwrap();
+            let after_put = store.changes_since(0, inbox.0).unwrap().modseq;
+
+            store.set_flags(&docs(&[id]), &[Flag::Seen]).unwrap();
+            store.modify(&docs(&[id]), vec![], vec![inbox.0]).unwrap();
+            store.modify(&docs(&[id]), vec![inbox.0], vec![]).unwrap();
+            store.set_flags(&docs(&[other]), &[Flag::Seen]).unwrap();
+
+            let all = store.changes_since(0, inbox.0).unwrap();
+            let kinds: Vec<Change> = all.changes.iter().map(|c| c.1.clone()).collect();
+            assert_eq!(
+                kinds,
+                vec![
+                    Change::Add(docs(&[id])),
+                    Change::Flags(docs(&[id])),
+                    Change::Remove(docs(&[id])),
+                    Change::Add(docs(&[id])),
+                ]
+            );
+            let modseqs: Vec<u64> = all.changes.iter().map(|c| c.0).collect();
+            assert!(modseqs.windows(2).all(|w| w[0] < w[1]));
+            // the high-water mark covers the changes of every collection
+            assert!(all.modseq > modseqs[3]);
+
+            let since = store.changes_since(after_put, inbox.0).unwrap();
+            assert_eq!(since.changes, all.changes[1..].to_vec());
+            let none = store.changes_since(all.modseq, inbox.0).unwrap();
+            assert!(none.changes.is_empty());
+            assert_eq!(none.modseq, all.modseq);
+        });
+    }
+
+    #[test]
+    fn test_uids() {
+        with_store("uids", |store| {
+            let inbox = store.create_collection("INBOX".to_string()).unwrap();
+            let archive = store.create_collection("Archive".to_string()).unwrap();
+            let a = store.put(&vec![inbox.0], &msg("", "a", 0)).unwrap();
+            let batch = store
+                .put_batch(vec![(vec![inbox.0], msg("", "b", 0)), (vec![inbox.0, archive.0], msg("", "c", 0))])
+                .unwrap();
+            let (b, c) = (batch[0], batch[1]);
+            store.modify(&docs(&[a]), vec![archive.0], vec![]).unwrap();
+
+            let uid = |col: u32, doc: u32| store.uid(col, doc).unwrap().unwrap();
+            assert!(uid(inbox.0, a) < uid(inbox.0, b));
+            assert!(uid(inbox.0, b) < uid(inbox.0, c));
+            assert!(uid(archive.0, c) < uid(archive.0, a));
+            for &(col, doc) in [(inbox.0, a), (inbox.0, b), (inbox.0, c), (archive.0, a), (archive.0, c)].iter() {
+                assert_eq!(store.doc_id_by_uid(col, uid(col, doc)).unwrap(), Some(doc));
+            }
+
+            // a message leaving and coming back gets a new UID
+            let before = uid(inbox.0, a);
+            store.modify(&docs(&[a]), vec![], vec![inbox.0]).unwrap();
+            assert_eq!(store.uid(inbox.0, a).unwrap(), None);
+            assert_eq!(store.doc_id_by_uid(inbox.0, before).unwrap(), None);
+            store.modify(&docs(&[a]), vec![inbox.0], vec![]).unwrap();
+            assert!(uid(inbox.0, a) > uid(inbox.0, c));
+            // adding a message already in the collection keeps its UID
+            let kept = uid(inbox.0, b);
+            store.modify(&docs(&[b]), vec![inbox.0], vec![]).unwrap();
+            assert_eq!(uid(inbox.0, b), kept);
+        });
+    }
+
+    #[test]
+    fn test_uid_validity_increases() {
+        with_store("uidvalidity", |store| {
+            let col = store.create_collection("INBOX".to_string()).unwrap();
+            let first = store.uid_validity(col.0).unwrap();
+            store.delete_collection(col.0, DeleteMode::Untag).unwrap();
+            // recreated within the same second
+            let col = store.create_collection("INBOX".to_string()).unwrap();
+            assert!(store.uid_validity(col.0).unwrap() > first);
+        });
+    }
+
+    fn part(mime_type: &str, headers: &[(&str, &str)], size: u64, parts: Vec<MsgPart>) -> MsgPart {
+        MsgPart {
+            headers: headers.iter().map(|h| (h.0.to_string(), h.1.to_string())).collect(),
+            part_id: String::new(),
+            mime_type: mime_type.to_string(),
+            size: size,
+            parts: parts,
+        }
+    }
+
+    #[test]
+    fn test_attachments() {
+        let report = part(
+            "application/pdf",
+            &[("content-disposition", "attachment; filename=\"q3; final.pdf\"")],
+            1000,
+            vec![],
+        );
+        let resume = part(
+            "application/pdf",
+            &[("Content-Type", "application/pdf; name*=UTF-8''r%C3%A9sum%C3%A9.pdf")],
+            5000,
+            vec![],
+        );
+        let unnamed = part("image/png", &[("Content-Disposition", "attachment")], 200, vec![]);
+        let inline = part("text/html", &[("Content-Disposition", "inline")], 10, vec![]);
+        let body = part("multipart/alternative", &[], 0, vec![part("text/plain", &[], 10, vec![]), inline.clone()]);
+        let root = part("multipart/mixed", &[], 0, vec![body, report.clone(), part("multipart/related", &[], 0, vec![
+            resume.clone(),
+            unnamed.clone(),
+        ])]);
+
+        assert_eq!(report.filename(), Some("q3; final.pdf".to_string()));
+        assert_eq!(resume.filename(), Some("résumé.pdf".to_string()));
+        assert_eq!(unnamed.filename(), None);
+        assert_eq!(part("text/plain", &[("Content-Disposition", "attachment; filename=\"\"")], 1, vec![]).filename(), None);
+
+        assert!(report.is_attachment());
+        assert!(resume.is_attachment());
+        assert!(unnamed.is_attachment());
+        assert!(!inline.is_attachment());
+        assert!(!root.is_attachment());
+        // a named multipart is a container, not an attachment
+        assert!(!part("multipart/mixed", &[("Content-Type", "multipart/mixed; name=x")], 0, vec![inline]).is_attachment());
+
+        assert_eq!(root.attachments(), vec![&report, &resume, &unnamed]);
+    }
+
+    #[test]
+    fn test_find_attachments() {
+        with_store("attachments", |store| {
+            let inbox = store.create_collection("INBOX".to_string()).unwrap();
+            let mut with = msg("report", "see attached", 0);
+            with.mime = Some(part("multipart/mixed", &[], 0, vec![
+                part("text/plain", &[], 12, vec![]),
+                part("application/pdf", &[("Content-Disposition", "attachment; filename*=UTF-8''r%C3%A9sum%C3%A9.pdf")], 1000, vec![]),
+                part("image/png", &[("Content-Disposition", "attachment; filename=\"logo; small.png\"")], 100, vec![]),
+            ]));
+            let mut inline = msg("inline", "no attachment", 0);
+            inline.mime = Some(part("text/plain", &[("Content-Disposition", "inline")], 12, vec![]));
+            let ids = store
+                .put_batch(vec![(vec![inbox.0], with), (vec![inbox.0], inline), (vec![inbox.0], msg("plain", "plain", 0))])
+                .unwrap();
+            let id = ids[0];
+
+            assert_eq!(store.find_with_attachments().unwrap(), docs(&[id]));
+            assert_eq!(store.query(&Query::HasAttachment).unwrap(), docs(&[id]));
+            assert_eq!(store.find(Field::Filename, "résumé").unwrap(), Some(docs(&[id])));
+            assert_eq!(store.find(Field::Filename, "logo small").unwrap(), Some(docs(&[id])));
+            assert_eq!(store.find(Field::Filename, "pdf").unwrap(), Some(docs(&[id])));
+            assert_eq!(store.find(Field::MimeType, "image").unwrap(), Some(docs(&[id])));
+
+            assert_eq!(store.find_attachment_size(100, 1000).unwrap(), docs(&[id]));
+            assert_eq!(store.find_attachment_size(101, 1000).unwrap(), docs(&[]));
+            assert_eq!(store.find_attachment_size(1000, 1001).unwrap(), docs(&[id]));
+            assert_eq!(store.find_attachment_size(1001, u64::max_value()).unwrap(), docs(&[]));
+            // the size of inline parts is not indexed
+            assert_eq!(store.find_attachment_size(0, 100).unwrap(), docs(&[]));
+            assert_eq!(store.query(&Query::Larger(999)).unwrap(), docs(&[id]));
+            assert_eq!(store.query(&Query::Larger(1000)).unwrap(), docs(&[]));
+            assert_eq!(store.query(&Query::Smaller(101)).unwrap(), docs(&[id]));
+            assert_eq!(store.query(&Query::Smaller(100)).unwrap(), docs(&[]));
+        });
+    }
+
+    #[test]
+    fn test_find_header() {
+        let path = ::std::env::temp_dir().join(format!("rocky-test-headers-{}", ::std::process::id()));
+        let _ = ::std::fs::remove_dir_all(&path);
+        for name in ["x#y", "x:y", "x y", ""].iter() {
+            let config = StoreConfig::default().header(name, HeaderIndex::Keyword);
+            match Store::open_with_config(path.to_str().unwrap(), config) {
+                Err(StoreError::InvalidArgument(_)) => {}
+                r => panic!("unexpected {:?}", r),
+            }
+        }
+        {
+            let config = StoreConfig::default()
+                .header("List-Id", HeaderIndex::Keyword)
+                .header("X-Note", HeaderIndex::Text);
+            let store = Store::open_with_config(path.to_str().unwrap(), config).unwrap();
+            let header = |name: &str, value: &str| (name.to_string(), value.to_string());
+            let mut first = msg("", "", 0);
+            first.headers = vec![
+                header("list-id", " Rust Users <users.rust-lang.org> "),
+                header("X-Note", "Quarterly report, final"),
+                header("X-Other", "report"),
+            ];
+            let mut second = msg("", "", 0);
+            second.headers = vec![header("LIST-ID", "dev <dev.rust-lang.org>"), header("x-note", "final draft")];
+            let ids = store.put_batch(vec![(vec![], first), (vec![], second)]).unwrap();
+
+            // keyword headers match the whole value, case-insensitively
+            assert_eq!(store.find_header("List-Id", "rust users <USERS.rust-lang.org>").unwrap(), docs(&ids[..1]));
+            assert_eq!(store.find_header("list-id", "rust").unwrap(), docs(&[]));
+            // text headers match every term of the value, in any order
+            assert_eq!(store.find_header("x-note", "final").unwrap(), docs(&ids));
+            assert_eq!(store.find_header("X-NOTE", "final quarterly").unwrap(), docs(&ids[..1]));
+            assert_eq!(store.find_header("x-note", "report draft").unwrap(), docs(&[]));
+            // headers which are not configured are not indexed
+            assert_eq!(store.find_header("x-other", "report").unwrap(), docs(&[]));
+            assert_eq!(store.query(&Query::header("X-Note", "draft")).unwrap(), docs(&ids[1..]));
+        }
+        ::std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    fn reply(subject: &str, message_id: &str, in_reply_to: &[&str], references: &[&str]) -> Msg {
+        let mut m = msg(subject, "", 0);
+        m.message_id = Some(message_id.to_string());
+        m.in_reply_to = in_reply_to.iter().map(|id| id.to_string()).collect();
+        m.references = references.iter().map(|id| id.to_string()).collect();
+        m
+    }
+
+    #[test]
+    fn test_threads() {
+        with_store("threads", |store| {
+            let put = |m: Msg| store.put(&vec![], &m).unwrap();
+            let thread = |id: u32| store.thread_of(id).unwrap().unwrap();
+
+            // a reply joins its parent, from In-Reply-To or References
+            let root = put(reply("Plans", "a@x", &[], &[]));
+            let answer = put(reply("Re: Plans", "b@x", &["a@x"], &[]));
+            let deep = put(reply("Other subject", "c@x", &[], &["a@x", "b@x"]));
+            assert_eq!(thread(root), root);
+            assert_eq!(thread(answer), root);
+            assert_eq!(thread(deep), root);
+
+            // a parent stored after its replies joins their thread
+            let late_reply = put(reply("Re: Late", "e@x", &["d@x"], &[]));
+            let late_parent = put(reply("Late", "d@x", &[], &[]));
+            assert_eq!(thread(late_parent), late_reply);
+
+            // same in a single batch
+            let ids = store
+                .put_batch(vec![(vec![], reply("Re: Batch", "g@x", &["f@x"], &[])), (vec![], reply("Batch", "f@x", &[], &[]))])
+                .unwrap();
+            assert_eq!(thread(ids[1]), ids[0]);
+
+            // the subject is only a fallback for replies without known parents
+            let same_subject = put(reply("plans", "h@x", &[], &[]));
+            assert_eq!(thread(same_subject), same_subject);
+            let by_subject = put(reply("[list] RE: Plans", "i@x", &[], &[]));
+            assert_eq!(thread(by_subject), same_subject);
+            let unknown_parent = put(reply("Plans", "j@x", &["unknown@x"], &[]));
+            assert_eq!(thread(unknown_parent), same_subject);
+
+            assert_eq!(store.thread_of(unknown_parent + 100).unwrap(), None);
+            assert_eq!(store.conversation(root).unwrap(), docs(&[root, answer, deep]));
+            assert_eq!(store.conversation(deep).unwrap(), docs(&[root, answer, deep]));
+            assert_eq!(store.conversation(late_parent).unwrap(), docs(&[late_reply, late_parent]));
+            assert_eq!(store.find_thread(same_subject).unwrap(), docs(&[same_subject, by_subject, unknown_parent]));
+            assert_eq!(store.query(&Query::Thread(root)).unwrap(), docs(&[root, answer, deep]));
+
+            assert_eq!(
+                store
+                    .collapse_threads(&[deep, late_parent, root, by_subject, late_reply, same_subject, unknown_parent + 100])
+                    .unwrap(),
+                vec![deep, late_parent, by_subject, unknown_parent + 100]
+            );
+        });
+    }
+
+    #[test]
+    fn test_find_cjk() {
+        with_store("cjk", |store| {
+            let ids = store
+                .put_batch(vec![(vec![], msg("", "東京都に住む", 0)), (vec![], msg("", "京都 and 東京", 0))])
+                .unwrap();
+
+            // single characters match anywhere in a run, including its last character
+            assert_eq!(store.find(Field::Body, "京").unwrap(), Some(docs(&ids)));
+            assert_eq!(store.find(Field::Body, "都").unwrap(), Some(docs(&ids)));
+            assert_eq!(store.find(Field::Body, "む").unwrap(), Some(docs(&ids[..1])));
+            assert_eq!(store.find(Field::Body, "東京").unwrap(), Some(docs(&ids)));
+            assert_eq!(store.find(Field::Body, "東京都").unwrap(), Some(docs(&ids[..1])));
+            assert_eq!(store.find(Field::Body, "京都").unwrap(), Some(docs(&ids)));
+            assert_eq!(store.find(Field::Body, "都東").unwrap().unwrap_or_default(), docs(&[]));
+        });
+    }
 }
@@ -0,0 +1,100 @@
+//! Helpers to thread messages from their Message-ID, In-Reply-To, References and subject headers.
+
+/// Extracts the `<id>` message ids of a Message-ID, In-Reply-To or References header
+/// value, without their angle brackets, in header order.
+pub fn parse_message_ids(value: &str) -> Vec<String> {
+    let mut ret = vec![];
+    let mut rest = value;
+    while let Some(start) = rest.find('<') {
+        match rest[start..].find('>') {
+            Some(end) => {
+                let id = rest[start + 1..start + end].trim();
+                if !id.is_empty() {
+                    ret.push(id.to_string());
+                }
+                rest = &rest[start + end + 1..];
+            }
+            None => break,
+        }
+    }
+    ret
+}
+
+/// Subject without its reply and forward prefixes (`Re:`, `Fwd:`, `Re[2]:`...) and
+/// mailing list tags (`[list]`), lowercased, along with whether a reply or forward
+/// prefix was found.
+pub fn base_subject(subject: &str) -> (String, bool) {
+    let mut s = subject.trim();
+    let mut reply = false;
+    loop {
+        if s.starts_with('[') {
+            if let Some(end) = s.find(']') {
+                s = s[end + 1..].trim_start();
+                continue;
+            }
+        }
+        match reply_prefix_len(s) {
+            Some(len) => {
+                s = s[len..].trim_start();
+                reply = true;
+            }
+            None => break,
+        }
+    }
+    (s.to_lowercase(), reply)
+}
+
+fn reply_prefix_len(s: &str) -> Option<usize> {
+    let lower = s.to_lowercase();
+    for prefix in ["re", "fwd", "fw"].iter() {
+        if !lower.starts_with(prefix) {
+            continue;
+        }
+        let rest = &lower[prefix.len()..];
+        // optional reply counter, as in `Re[2]:`
+        let counter = if rest.starts_with('[') {
+            match rest.find(']') {
+                Some(end) if rest[1..end].chars().all(|c| c.is_ascii_digit()) => end + 1,
+                _ => continue,
+            }
+        } else {
+            0
+        };
+        if rest[counter..].starts_with(':') {
+            return Some(prefix.len() + counter + 1);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_message_ids() {
+        assert_eq!(parse_message_ids("<a@x> <b@y>\r\n <c@z>"), vec!["a@x", "b@y", "c@z"]);
+        assert_eq!(parse_message_ids(" < a@x > "), vec!["a@x"]);
+        assert!(parse_message_ids("<> no id <unterminated").is_empty());
+    }
+
+    #[test]
+    fn test_base_subject() {
+        assert_eq!(base_subject("Hello"), ("hello".to_string(), false));
+        assert_eq!(base_subject("Re: Hello"), ("hello".to_string(), true));
+        assert_eq!(base_subject("RE: Fwd: re[2]: Hello "), ("hello".to_string(), true));
+        assert_eq!(base_subject("[list] Re: [list] Hello"), ("hello".to_string(), true));
+        assert_eq!(base_subject("FW:Hello"), ("hello".to_string(), true));
+    }
+
+    #[test]
+    fn test_base_subject_edge_cases() {
+        assert_eq!(base_subject(""), ("".to_string(), false));
+        assert_eq!(base_subject("Re:"), ("".to_string(), true));
+        // not prefixes
+        assert_eq!(base_subject("Regarding: x"), ("regarding: x".to_string(), false));
+        assert_eq!(base_subject("Re[a]: x"), ("re[a]: x".to_string(), false));
+        assert_eq!(base_subject("[unclosed Re: x"), ("[unclosed re: x".to_string(), false));
+        assert_eq!(base_subject("Ré: été"), ("ré: été".to_string(), false));
+    }
+}